@@ -1,53 +1,239 @@
 //! structs from the esplora API
 //!
 //! see: <https://github.com/Blockstream/esplora/blob/master/API.md>
-use crate::BlockTime;
-use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid, Witness};
+use crate::error::Error;
+use crate::{BlockTime, FeeRate};
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::{BlockHash, OutPoint, Script, Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness};
 
+use super::EsploraError;
+
+/// Output spent by a transaction input
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct PrevOut {
+    /// Value of the output in satoshis
+    #[cfg(not(feature = "elements"))]
     pub value: u64,
+    /// Value of the output in satoshis, `None` if the amount is confidential
+    #[cfg(feature = "elements")]
+    pub value: Option<u64>,
+    /// Asset id of the output, `None` if the asset is confidential
+    #[cfg(feature = "elements")]
+    pub asset: Option<String>,
+    /// Script pubkey of the output
     pub scriptpubkey: Script,
 }
 
+/// Transaction input
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Vin {
+    /// Txid of the transaction being spent
     pub txid: Txid,
+    /// Index of the output being spent
     pub vout: u32,
-    // None if coinbase
+    /// Output being spent, `None` if coinbase
     pub prevout: Option<PrevOut>,
+    /// Script sig of the input
     pub scriptsig: Script,
+    /// Witness stack of the input
     #[serde(deserialize_with = "deserialize_witness", default)]
     pub witness: Vec<Vec<u8>>,
+    /// Sequence number of the input
     pub sequence: u32,
+    /// Whether this is the input of a coinbase transaction
     pub is_coinbase: bool,
+    /// Whether this input is a peg-in from the parent chain
+    #[cfg(feature = "elements")]
+    #[serde(default)]
+    pub is_pegin: bool,
 }
 
+/// Transaction output
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Vout {
+    /// Value of the output in satoshis
+    #[cfg(not(feature = "elements"))]
     pub value: u64,
+    /// Value of the output in satoshis, `None` if the amount is confidential
+    #[cfg(feature = "elements")]
+    pub value: Option<u64>,
+    /// Asset id of the output, `None` if the asset is confidential
+    #[cfg(feature = "elements")]
+    pub asset: Option<String>,
+    /// Script pubkey of the output
     pub scriptpubkey: Script,
 }
 
-#[derive(serde::Deserialize, Clone, Debug)]
+/// Confirmation status of a transaction
+///
+/// Older or forked servers omit some of the block fields or send `block_time` as a string, so
+/// missing fields are `None` and a numeric string is accepted as timestamp.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TxStatus {
+    /// Whether the transaction is confirmed
     pub confirmed: bool,
+    /// Height of the block the transaction was confirmed in
+    #[serde(default)]
     pub block_height: Option<u32>,
+    /// Hash of the block the transaction was confirmed in
+    #[serde(default)]
+    pub block_hash: Option<BlockHash>,
+    /// Timestamp of the block the transaction was confirmed in
+    #[serde(deserialize_with = "deserialize_block_time", default)]
     pub block_time: Option<u64>,
 }
 
+/// Spending status of a transaction output, as returned by the `/tx/:txid/outspend/:vout` endpoint
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OutputStatus {
+    /// Whether the output is spent, either in the mempool or in the chain
+    pub spent: bool,
+    /// Txid of the spending transaction
+    pub txid: Option<Txid>,
+    /// Index of the spending input in the spending transaction
+    pub vin: Option<u32>,
+    /// Confirmation status of the spending transaction
+    pub status: Option<TxStatus>,
+}
+
+/// Merkle inclusion proof of a transaction, as returned by the `/tx/:txid/merkle-proof` endpoint
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Height of the block the transaction was confirmed in
+    pub block_height: u32,
+    /// Sibling hashes on the path from the transaction up to the merkle root
+    pub merkle: Vec<Txid>,
+    /// Position of the transaction in the block
+    pub pos: usize,
+}
+
+impl MerkleProof {
+    /// Recompute the merkle root for `txid` using this proof and compare it to `expected_root`
+    ///
+    /// Returns an error if `pos` can't be a position in a tree of the depth given by `merkle`.
+    pub fn verify(&self, txid: &Txid, expected_root: TxMerkleNode) -> Result<bool, Error> {
+        // a tree with `n` levels has at most `2^n` leaves. If `merkle` is empty the transaction
+        // is the only one in the block and its txid is the merkle root.
+        let in_range = match 1usize.checked_shl(self.merkle.len() as u32) {
+            Some(max_leaves) => self.pos < max_leaves,
+            None => true,
+        };
+        if !in_range {
+            return Err(EsploraError::InvalidMerkleProof(*txid).into());
+        }
+
+        let mut pos = self.pos;
+        let mut current = TxMerkleNode::from_inner(txid.into_inner());
+        for sibling in &self.merkle {
+            let mut engine = TxMerkleNode::engine();
+            if pos & 1 == 0 {
+                engine.input(&current[..]);
+                engine.input(&sibling[..]);
+            } else {
+                engine.input(&sibling[..]);
+                engine.input(&current[..]);
+            }
+            current = TxMerkleNode::from_engine(engine);
+            pos >>= 1;
+        }
+
+        Ok(current == expected_root)
+    }
+}
+
+/// Statistics about the outputs funded and spent by a script, as returned by the
+/// `/scripthash/:hash` endpoint
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AddressStats {
+    /// Statistics of the confirmed transactions
+    pub chain_stats: TxoStats,
+    /// Statistics of the unconfirmed transactions
+    pub mempool_stats: TxoStats,
+}
+
+/// Count and sum of the outputs funded and spent by a script
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct TxoStats {
+    /// Number of outputs funded
+    pub funded_txo_count: u64,
+    /// Sum in satoshis of the outputs funded
+    pub funded_txo_sum: u64,
+    /// Number of outputs spent
+    pub spent_txo_count: u64,
+    /// Sum in satoshis of the outputs spent
+    pub spent_txo_sum: u64,
+    /// Number of transactions
+    pub tx_count: u64,
+}
+
+impl AddressStats {
+    /// Balance in satoshis considering only confirmed transactions
+    pub fn confirmed_balance(&self) -> u64 {
+        self.chain_stats
+            .funded_txo_sum
+            .saturating_sub(self.chain_stats.spent_txo_sum)
+    }
+
+    /// Change in balance in satoshis brought by the unconfirmed transactions
+    pub fn unconfirmed_balance(&self) -> i64 {
+        self.mempool_stats.funded_txo_sum as i64 - self.mempool_stats.spent_txo_sum as i64
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct MempoolInfo {
+    /// `(feerate, vsize)` buckets, where `vsize` is the size of the transactions paying between
+    /// this and the next bucket's feerate
+    pub fee_histogram: Vec<(f64, u64)>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct BlockInfo {
+    /// Timestamp of the block header
+    pub timestamp: u64,
+}
+
+/// Summary of a block, as returned by the `/blocks/:start_height` endpoint
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockSummary {
+    /// Hash of the block
+    pub id: BlockHash,
+    /// Height of the block
+    pub height: u32,
+    /// Timestamp of the block header
+    pub timestamp: u64,
+    /// Number of transactions in the block
+    pub tx_count: u32,
+    /// Size of the block in bytes
+    pub size: u32,
+    /// Weight of the block in weight units
+    pub weight: u32,
+}
+
+/// Transaction as returned by the verbose esplora endpoints
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Tx {
+    /// Txid of the transaction
     pub txid: Txid,
+    /// Version of the transaction
     pub version: i32,
+    /// Lock time of the transaction
     pub locktime: u32,
+    /// Inputs of the transaction
     pub vin: Vec<Vin>,
+    /// Outputs of the transaction
     pub vout: Vec<Vout>,
+    /// Confirmation status of the transaction
     pub status: TxStatus,
+    /// Fee paid by the transaction in satoshis
     pub fee: u64,
 }
 
 impl Tx {
+    /// Convert to a [`Transaction`]
+    ///
+    /// With the `elements` feature confidential amounts are converted to a value of zero, see
+    /// [`Tx::confidential_scripts`].
     pub fn to_tx(&self) -> Transaction {
         Transaction {
             version: self.version,
@@ -71,24 +257,39 @@ impl Tx {
                 .iter()
                 .cloned()
                 .map(|vout| TxOut {
-                    value: vout.value,
+                    value: txout_value(vout.value),
                     script_pubkey: vout.scriptpubkey,
                 })
                 .collect(),
         }
     }
 
+    /// Return the fee rate paid by the transaction, from its fee and the weight of
+    /// [`Tx::to_tx`]
+    ///
+    /// With the `elements` feature the weight doesn't include the confidential data, which
+    /// overestimates the fee rate.
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::from_wu(self.fee, self.to_tx().weight())
+    }
+
+    /// Return the [`BlockTime`] of the transaction if it's confirmed
     pub fn confirmation_time(&self) -> Option<BlockTime> {
         match self.status {
             TxStatus {
                 confirmed: true,
                 block_height: Some(height),
                 block_time: Some(timestamp),
+                ..
             } => Some(BlockTime { timestamp, height }),
             _ => None,
         }
     }
 
+    /// Return the outputs spent by each input, `None` for coinbase inputs
+    ///
+    /// With the `elements` feature confidential amounts are converted to a value of zero, see
+    /// [`Tx::confidential_scripts`].
     pub fn previous_outputs(&self) -> Vec<Option<TxOut>> {
         self.vin
             .iter()
@@ -96,11 +297,38 @@ impl Tx {
             .map(|vin| {
                 vin.prevout.map(|po| TxOut {
                     script_pubkey: po.scriptpubkey,
-                    value: po.value,
+                    value: txout_value(po.value),
                 })
             })
             .collect()
     }
+
+    /// Return the scripts of the outputs created or spent by the transaction whose amount is
+    /// confidential
+    #[cfg(feature = "elements")]
+    pub fn confidential_scripts(&self) -> impl Iterator<Item = &Script> {
+        let spent = self
+            .vin
+            .iter()
+            .filter_map(|vin| vin.prevout.as_ref())
+            .filter(|po| po.value.is_none())
+            .map(|po| &po.scriptpubkey);
+        self.vout
+            .iter()
+            .filter(|vout| vout.value.is_none())
+            .map(|vout| &vout.scriptpubkey)
+            .chain(spent)
+    }
+}
+
+#[cfg(not(feature = "elements"))]
+fn txout_value(value: u64) -> u64 {
+    value
+}
+
+#[cfg(feature = "elements")]
+fn txout_value(value: Option<u64>) -> u64 {
+    value.unwrap_or(0)
 }
 
 fn deserialize_witness<'de, D>(d: D) -> Result<Vec<Vec<u8>>, D::Error>
@@ -111,7 +339,35 @@ where
     use bitcoin::hashes::hex::FromHex;
     let list = Vec::<String>::deserialize(d)?;
     list.into_iter()
-        .map(|hex_str| Vec::<u8>::from_hex(&hex_str))
-        .collect::<Result<Vec<Vec<u8>>, _>>()
-        .map_err(serde::de::Error::custom)
+        .enumerate()
+        .map(|(index, hex_str)| {
+            Vec::<u8>::from_hex(&hex_str).map_err(|e| {
+                serde::de::Error::custom(format!("invalid hex in witness element {}: {}", index, e))
+            })
+        })
+        .collect()
+}
+
+fn deserialize_block_time<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    use crate::serde::Deserialize;
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Number(u64),
+        String(String),
+    }
+
+    match Option::<Timestamp>::deserialize(d)? {
+        Some(Timestamp::Number(time)) => Ok(Some(time)),
+        Some(Timestamp::String(time)) => {
+            time.trim().parse().map(Some).map_err(|e| {
+                serde::de::Error::custom(format!("invalid block time {:?}: {}", time, e))
+            })
+        }
+        None => Ok(None),
+    }
 }
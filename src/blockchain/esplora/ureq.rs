@@ -15,30 +15,63 @@ use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Read;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 #[allow(unused_imports)]
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
-use ureq::{Agent, Proxy, Response};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde::de::DeserializeOwned;
+use ureq::{Agent, Proxy, Request, Response};
 
 use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
-use bitcoin::hashes::{sha256, Hash};
-use bitcoin::{BlockHeader, Script, Transaction, Txid};
+#[cfg(feature = "esplora-filters")]
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{
+    Address, Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid,
+};
 
-use super::api::Tx;
-use crate::blockchain::esplora::EsploraError;
+use super::api::{
+    AddressStats, BlockInfo, BlockSummary, MempoolInfo, MerkleProof, OutputStatus, Tx, TxStatus,
+};
+#[cfg(feature = "elements")]
+use crate::blockchain::esplora::check_confidential;
+use crate::blockchain::esplora::{
+    accept_header, accepted_by_any, checked_pow, confirmed_below, ignore_already_known, is_html,
+    missing_unconfirmed, reached_height, recent_activity, roll_back, script_hash, scripts_update,
+    BaseUrls, BlockHashCache, CancellationToken, Checkpoints, ConfirmationInfo, EsploraError,
+    EsploraObserver, FeePriorities, FirstSeen, GapLimit, HealthInfo, InclusionProof, KeychainScan,
+    LastActiveIndexes, NetworkCheck, NewTxCallback, Observer, PendingInputs, ProgressCallback,
+    RateLimiter, RetryConfig, ScriptFilter, SyncPlan, SyncProgress, Timeouts, TxCache,
+};
 use crate::blockchain::*;
 use crate::database::BatchDatabase;
 use crate::error::Error;
-use crate::FeeRate;
+use crate::wallet::time::{get_timestamp, Instant};
+use crate::{BlockTime, FeeRate, KeychainKind};
 
 /// Structure encapsulates ureq Esplora client
 #[derive(Debug, Clone)]
 pub struct UrlClient {
-    url: String,
+    urls: Arc<BaseUrls>,
     agent: Agent,
+    retry: Option<RetryConfig>,
+    headers: Vec<(String, String)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    tx_cache: Option<Arc<TxCache>>,
+    block_hashes: Arc<BlockHashCache>,
+    timeouts: Timeouts,
+    gzip: bool,
+    // shared by the clones used to send parallel requests
+    request_count: Arc<AtomicU64>,
+    observer: Option<Observer>,
+    max_response_bytes: Option<usize>,
+    strict_broadcast: bool,
+    verify_header_pow: bool,
 }
 
 /// Structure that implements the logic to sync with Esplora
@@ -49,19 +82,71 @@ pub struct UrlClient {
 pub struct EsploraBlockchain {
     url_client: UrlClient,
     stop_gap: usize,
+    stop_gaps: HashMap<KeychainKind, usize>,
+    gap_overshoot: usize,
     concurrency: u8,
+    reorg_depth: u32,
+    verify_merkle_proofs: bool,
+    deterministic: bool,
+    script_filter: bool,
+    min_fee_rate: Option<FeeRate>,
+    // shared with the clients returned by `clone_with_concurrency`
+    checkpoints: Arc<Checkpoints>,
+    first_seen: Arc<FirstSeen>,
+    pending_inputs: Arc<PendingInputs>,
+    last_active_indexes: Arc<LastActiveIndexes>,
+    network_check: Option<Arc<NetworkCheck>>,
+    cancellation_token: CancellationToken,
+    sync_progress: Option<ProgressCallback>,
+    on_new_tx: Option<NewTxCallback>,
 }
 
 impl EsploraBlockchain {
     /// Create a new instance of the client from a base URL and the `stop_gap`.
     pub fn new(base_url: &str, stop_gap: usize) -> Self {
+        Self::from_agent(base_url.to_string(), stop_gap, Agent::new())
+    }
+
+    /// Create a new instance from a base URL and `stop_gap`, sending the requests with `agent`
+    ///
+    /// This allows reusing an agent shared with the rest of an application, with its own TLS,
+    /// proxy and middleware configuration. Equivalent to [`EsploraBlockchain::new`] followed by
+    /// [`EsploraBlockchain::with_agent`].
+    pub fn from_agent(base_url: String, stop_gap: usize, agent: Agent) -> Self {
         EsploraBlockchain {
             url_client: UrlClient {
-                url: base_url.to_string(),
-                agent: Agent::new(),
+                urls: Arc::new(BaseUrls::new(&base_url, &[])),
+                agent,
+                retry: None,
+                headers: Vec::new(),
+                rate_limiter: None,
+                tx_cache: None,
+                block_hashes: Arc::new(BlockHashCache::new(super::DEFAULT_REORG_DEPTH)),
+                timeouts: Timeouts::default(),
+                gzip: true,
+                request_count: Arc::new(AtomicU64::new(0)),
+                observer: None,
+                max_response_bytes: None,
+                strict_broadcast: false,
+                verify_header_pow: false,
             },
             concurrency: super::DEFAULT_CONCURRENT_REQUESTS,
             stop_gap,
+            stop_gaps: HashMap::new(),
+            gap_overshoot: 0,
+            reorg_depth: super::DEFAULT_REORG_DEPTH,
+            verify_merkle_proofs: false,
+            deterministic: false,
+            script_filter: false,
+            min_fee_rate: None,
+            checkpoints: Arc::new(Checkpoints::default()),
+            first_seen: Arc::new(FirstSeen::default()),
+            pending_inputs: Arc::new(PendingInputs::default()),
+            last_active_indexes: Arc::new(LastActiveIndexes::default()),
+            network_check: None,
+            cancellation_token: CancellationToken::default(),
+            sync_progress: None,
+            on_new_tx: None,
         }
     }
 
@@ -71,122 +156,356 @@ impl EsploraBlockchain {
         self
     }
 
+    /// Set the base URLs of other esplora services, tried in order when a request fails with a
+    /// transport or server error.
+    pub fn with_fallback_urls(mut self, fallback_urls: &[String]) -> Self {
+        self.url_client.urls =
+            Arc::new(BaseUrls::new(self.url_client.urls.primary(), fallback_urls));
+        self
+    }
+
+    /// Override the stop gap of `keychain`, which otherwise uses the global `stop_gap`.
+    pub fn with_keychain_stop_gap(mut self, keychain: KeychainKind, stop_gap: usize) -> Self {
+        self.stop_gaps.insert(keychain, stop_gap);
+        self
+    }
+
+    /// Set the number of scripts scanned after reaching the stop gap, as a safety margin.
+    ///
+    /// Transactions found in this margin are still synced, with a warning logged since they mean
+    /// that the stop gap is too small.
+    pub fn with_gap_overshoot(mut self, gap_overshoot: usize) -> Self {
+        self.gap_overshoot = gap_overshoot;
+        self
+    }
+
+    /// Fail the broadcast of a transaction that the server already has in its mempool or in the
+    /// chain, instead of treating it as a success
+    pub fn with_strict_broadcast(mut self, strict: bool) -> Self {
+        self.url_client.strict_broadcast = strict;
+        self
+    }
+
+    /// Reject the block headers fetched from the server whose hash doesn't meet their difficulty
+    /// target, see [`super::verify_pow`]
+    pub fn with_verify_header_pow(mut self, verify_header_pow: bool) -> Self {
+        self.url_client.verify_header_pow = verify_header_pow;
+        self
+    }
+
+    /// Check that the esplora service serves `network` before the first request made through
+    /// the [`Blockchain`] traits, failing with [`Error::NetworkMismatch`] otherwise
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network_check = Some(Arc::new(NetworkCheck::new(network)));
+        self
+    }
+
     /// Set the number of parallel requests the client can make.
+    ///
+    /// Zero is treated as one, and values above
+    /// [`MAX_CONCURRENT_REQUESTS`](super::MAX_CONCURRENT_REQUESTS) are capped to it.
     pub fn with_concurrency(mut self, concurrency: u8) -> Self {
-        self.concurrency = concurrency;
+        self.concurrency = super::clamp_concurrency(concurrency);
         self
     }
-}
 
-impl Blockchain for EsploraBlockchain {
-    fn get_capabilities(&self) -> HashSet<Capability> {
-        vec![
-            Capability::FullHistory,
-            Capability::GetAnyTx,
-            Capability::AccurateFees,
-        ]
-        .into_iter()
-        .collect()
+    /// Return a client sharing the connections, caches and sync state of this one, but sending
+    /// up to `concurrency` parallel requests
+    ///
+    /// Useful to change the parallelism of some operations, e.g. raising it for an initial full
+    /// scan, without building a new client. `concurrency` is bounded as in
+    /// [`EsploraBlockchain::with_concurrency`].
+    pub fn clone_with_concurrency(&self, concurrency: u8) -> Self {
+        EsploraBlockchain {
+            url_client: self.url_client.clone(),
+            stop_gap: self.stop_gap,
+            stop_gaps: self.stop_gaps.clone(),
+            gap_overshoot: self.gap_overshoot,
+            concurrency: super::clamp_concurrency(concurrency),
+            reorg_depth: self.reorg_depth,
+            verify_merkle_proofs: self.verify_merkle_proofs,
+            deterministic: self.deterministic,
+            script_filter: self.script_filter,
+            min_fee_rate: self.min_fee_rate,
+            checkpoints: Arc::clone(&self.checkpoints),
+            first_seen: Arc::clone(&self.first_seen),
+            pending_inputs: Arc::clone(&self.pending_inputs),
+            last_active_indexes: Arc::clone(&self.last_active_indexes),
+            network_check: self.network_check.clone(),
+            cancellation_token: self.cancellation_token.clone(),
+            sync_progress: self.sync_progress.clone(),
+            on_new_tx: self.on_new_tx.clone(),
+        }
     }
 
-    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
-        self.url_client._broadcast(tx)?;
-        Ok(())
+    /// Set the number of blocks below the tip checked for reorgs at the beginning of every sync.
+    pub fn with_reorg_depth(mut self, reorg_depth: u32) -> Self {
+        self.reorg_depth = reorg_depth;
+        self.url_client.block_hashes.set_reorg_depth(reorg_depth);
+        self
     }
 
-    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
-        let estimates = self.url_client._get_fee_estimates()?;
-        super::into_fee_rate(target, estimates)
+    /// Set whether to verify the merkle proof of every confirmed transaction found during a sync.
+    pub fn with_verify_merkle_proofs(mut self, verify_merkle_proofs: bool) -> Self {
+        self.verify_merkle_proofs = verify_merkle_proofs;
+        self
     }
-}
 
-impl Deref for EsploraBlockchain {
-    type Target = UrlClient;
+    /// Set the minimum fee rate returned by the fee estimation.
+    pub fn with_min_fee_rate(mut self, min_fee_rate: FeeRate) -> Self {
+        self.min_fee_rate = Some(min_fee_rate);
+        self
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.url_client
+    /// Send the requests of a sync one at a time, in a fixed order, e.g. for reproducible tests.
+    pub fn with_deterministic_ordering(mut self) -> Self {
+        self.deterministic = true;
+        self
     }
-}
 
-impl StatelessBlockchain for EsploraBlockchain {}
+    /// Set whether to skip the transactions returned by the server that don't touch any script
+    /// of the wallet during a sync, see [`ScriptFilter`].
+    pub fn with_script_filter(mut self, script_filter: bool) -> Self {
+        self.script_filter = script_filter;
+        self
+    }
 
-impl GetHeight for EsploraBlockchain {
-    fn get_height(&self) -> Result<u32, Error> {
-        Ok(self.url_client._get_height()?)
+    /// Number of parallel requests sent during a sync
+    fn sync_concurrency(&self) -> usize {
+        if self.deterministic {
+            1
+        } else {
+            self.concurrency as usize
+        }
     }
-}
 
-impl GetTx for EsploraBlockchain {
-    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
-        Ok(self.url_client._get_tx(txid)?)
+    /// Set the token used to cancel a running sync.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
     }
-}
 
-impl GetBlockHash for EsploraBlockchain {
-    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
-        let block_header = self.url_client._get_header(height as u32)?;
-        Ok(block_header.block_hash())
+    /// Set a callback receiving the [`SyncProgress`] of the address scan after every batch of
+    /// requests.
+    ///
+    /// The callback is invoked from the sync itself, so it should return quickly, e.g. by
+    /// forwarding the progress over a channel.
+    pub fn with_sync_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(SyncProgress) + Send + Sync + 'static,
+    {
+        self.sync_progress = Some(ProgressCallback(Arc::new(callback)));
+        self
     }
-}
 
-impl WalletSync for EsploraBlockchain {
-    fn wallet_setup<D: BatchDatabase>(
+    /// Set a callback invoked with every transaction found by a sync that isn't in the database
+    /// yet
+    ///
+    /// The callback runs before the changes of the sync are written to the database, e.g. to
+    /// notify the user of an incoming payment or to annotate the transaction. It's shared with
+    /// the clients returned by [`EsploraBlockchain::clone_with_concurrency`], so any mutable state
+    /// should be kept behind a `Mutex`.
+    pub fn with_on_new_tx<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Tx) + Send + Sync + 'static,
+    {
+        self.on_new_tx = Some(NewTxCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Set an observer notified around every HTTP request sent to the esplora service.
+    pub fn with_observer(mut self, observer: Arc<dyn EsploraObserver>) -> Self {
+        self.url_client.observer = Some(Observer(observer));
+        self
+    }
+
+    /// Compare the genesis block of the service with the network set with
+    /// [`EsploraBlockchain::with_network`], unless it already matched
+    fn check_network(&self) -> Result<(), Error> {
+        match &self.network_check {
+            Some(check) if !check.is_checked() => {
+                check.verify(&self.url_client._get_block_hash(0)?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Compare the checkpoints stored by the previous syncs with the current chain, rolling back
+    /// the transactions confirmed in reorganized blocks, then checkpoint the current tip
+    fn check_reorg<D: BatchDatabase>(&self, database: &mut D) -> Result<(), Error> {
+        if self.reorg_depth == 0 {
+            return Ok(());
+        }
+
+        let tip = self.url_client._get_height()?;
+        let current = self
+            .checkpoints
+            .heights(tip, self.reorg_depth)
+            .into_iter()
+            .map(|height| {
+                if height <= tip {
+                    Ok((height, Some(self.url_client._get_block_hash(height)?)))
+                } else {
+                    Ok((height, None))
+                }
+            })
+            .collect::<Result<Vec<_>, EsploraError>>()?;
+
+        if let Some(fork_height) = self.checkpoints.verify(tip, self.reorg_depth, &current) {
+            info!("reorg detected, rolling back from height {}", fork_height);
+            self.url_client.block_hashes.forget_from(fork_height);
+            roll_back(database, fork_height)?;
+        }
+
+        let tip_hash = self.url_client._get_block_hash(tip)?;
+        self.checkpoints.push(tip, tip_hash, self.reorg_depth);
+
+        Ok(())
+    }
+
+    /// Limit of the scan of `keychain`, with its own stop gap
+    fn gap_limit<D: BatchDatabase>(
         &self,
-        database: &mut D,
-        _progress_update: Box<dyn Progress>,
+        database: &D,
+        keychain: KeychainKind,
+    ) -> Result<GapLimit, Error> {
+        let stop_gap = self
+            .stop_gaps
+            .get(&keychain)
+            .copied()
+            .unwrap_or(self.stop_gap);
+        GapLimit::new(database, keychain, stop_gap, self.gap_overshoot)
+    }
+
+    /// Fetch the history of the scripts of both keychains, scanning the keychains in parallel
+    /// with half of `concurrency` each, or one after the other when sending a single request at
+    /// a time
+    ///
+    /// The scan of each keychain stops after its own stop gap, the scripts that aren't scanned
+    /// are treated as unused by the script sync.
+    fn scan_keychains<D: BatchDatabase>(
+        &self,
+        database: &D,
+        min_height: u32,
+    ) -> Result<HashMap<KeychainKind, KeychainScan>, Error> {
+        let keychains = [KeychainKind::External, KeychainKind::Internal];
+        let script_filter = if self.script_filter {
+            Some(Arc::new(ScriptFilter::from_database(database)?))
+        } else {
+            None
+        };
+        if self.sync_concurrency() == 1 {
+            return keychains
+                .iter()
+                .map(|&keychain| {
+                    let scripts = database.iter_script_pubkeys(Some(keychain))?;
+                    let scan = self.url_client._scan_keychain(
+                        keychain,
+                        &scripts,
+                        Some(self.gap_limit(database, keychain)?),
+                        1,
+                        min_height,
+                        script_filter.as_deref(),
+                        &self.cancellation_token,
+                        self.sync_progress.as_ref(),
+                    )?;
+                    Ok((keychain, scan))
+                })
+                .collect();
+        }
+
+        let concurrency = (self.concurrency as usize / 2).max(1);
+        let handles = keychains
+            .iter()
+            .map(|&keychain| {
+                let scripts = database.iter_script_pubkeys(Some(keychain))?;
+                let limit = self.gap_limit(database, keychain)?;
+                let client = self.url_client.clone();
+                let cancellation_token = self.cancellation_token.clone();
+                let sync_progress = self.sync_progress.clone();
+                let script_filter = script_filter.clone();
+                let handle = std::thread::spawn(move || {
+                    client._scan_keychain(
+                        keychain,
+                        &scripts,
+                        Some(limit),
+                        concurrency,
+                        min_height,
+                        script_filter.as_deref(),
+                        &cancellation_token,
+                        sync_progress.as_ref(),
+                    )
+                });
+                Ok((keychain, handle))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        handles
+            .into_iter()
+            .map(|(keychain, handle)| Ok((keychain, handle.join().unwrap()?)))
+            .collect()
+    }
+
+    /// Invoke the `on_new_tx` callback, if set, with the transactions of `tx_index` that aren't
+    /// in `database` yet
+    fn notify_new_txs<D: BatchDatabase>(
+        &self,
+        database: &D,
+        tx_index: &HashMap<Txid, Tx>,
     ) -> Result<(), Error> {
+        match &self.on_new_tx {
+            Some(on_new_tx) => on_new_tx.notify(database, tx_index),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the script sync against `database` without writing to it, returning the batch update
+    /// to apply and the transactions found. With `since_height`, only the transactions confirmed
+    /// above the reorg window below it are fetched, see [`EsploraBlockchain::sync_from`].
+    fn fetch_sync<D: BatchDatabase>(
+        &self,
+        database: &D,
+        since_height: Option<u32>,
+    ) -> Result<(D::Batch, HashMap<Txid, Tx>), Error> {
         use crate::blockchain::script_sync::Request;
-        let mut request = script_sync::start(database, self.stop_gap)?;
+        let min_height = since_height
+            .map(|height| height.saturating_sub(self.reorg_depth))
+            .unwrap_or(0);
+        let known_txs = match since_height {
+            Some(_) => confirmed_below(database, min_height)?,
+            None => HashMap::new(),
+        };
+        let scans = self.scan_keychains(database, min_height)?;
+        self.last_active_indexes.record(&scans);
+        let mut request = script_sync::start_with_stop_gaps(
+            database,
+            self.stop_gap,
+            &self.stop_gaps,
+            self.gap_overshoot,
+        )?;
         let mut tx_index: HashMap<Txid, Tx> = HashMap::new();
         let batch_update = loop {
+            self.cancellation_token.check()?;
             request = match request {
                 Request::Script(script_req) => {
-                    let scripts = script_req
-                        .request()
-                        .take(self.concurrency as usize)
-                        .cloned();
-
-                    let mut handles = vec![];
-                    for script in scripts {
-                        let client = self.url_client.clone();
-                        // make each request in its own thread.
-                        handles.push(std::thread::spawn(move || {
-                            let mut related_txs: Vec<Tx> = client._scripthash_txs(&script, None)?;
-
-                            let n_confirmed =
-                                related_txs.iter().filter(|tx| tx.status.confirmed).count();
-                            // esplora pages on 25 confirmed transactions. If there's 25 or more we
-                            // keep requesting to see if there's more.
-                            if n_confirmed >= 25 {
-                                loop {
-                                    let new_related_txs: Vec<Tx> = client._scripthash_txs(
-                                        &script,
-                                        Some(related_txs.last().unwrap().txid),
-                                    )?;
-                                    let n = new_related_txs.len();
-                                    related_txs.extend(new_related_txs);
-                                    // we've reached the end
-                                    if n < 25 {
-                                        break;
-                                    }
-                                }
-                            }
-                            Result::<_, Error>::Ok(related_txs)
-                        }));
-                    }
-
-                    let txs_per_script: Vec<Vec<Tx>> = handles
-                        .into_iter()
-                        .map(|handle| handle.join().unwrap())
-                        .collect::<Result<_, _>>()?;
+                    let scan = scans.get(&script_req.keychain());
                     let mut satisfaction = vec![];
-
-                    for txs in txs_per_script {
-                        satisfaction.push(
-                            txs.iter()
-                                .map(|tx| (tx.txid, tx.status.block_height))
-                                .collect(),
-                        );
+                    for script in script_req.request() {
+                        let txs = scan
+                            .and_then(|scan| scan.histories.get(script))
+                            .cloned()
+                            .unwrap_or_default();
+                        let mut txids: Vec<_> = txs
+                            .iter()
+                            .map(|tx| (tx.txid, tx.status.block_height))
+                            .collect();
+                        // keep the older transactions that weren't fetched again
+                        for known in known_txs.get(script).into_iter().flatten() {
+                            if !txs.iter().any(|tx| tx.txid == known.0) {
+                                txids.push(*known);
+                            }
+                        }
+                        satisfaction.push(txids);
                         for tx in txs {
                             tx_index.insert(tx.txid, tx);
                         }
@@ -194,6 +513,26 @@ impl WalletSync for EsploraBlockchain {
 
                     script_req.satisfy(satisfaction)?
                 }
+                Request::Conftime(conftime_req) if self.verify_merkle_proofs => {
+                    let handles = conftime_req
+                        .request()
+                        .take(self.sync_concurrency())
+                        .map(|txid| {
+                            let client = self.url_client.clone();
+                            let txid = *txid;
+                            let conftime = tx_index
+                                .get(&txid)
+                                .expect("must be in index")
+                                .confirmation_time();
+                            std::thread::spawn(move || client._verified_conftime(&txid, conftime))
+                        })
+                        .collect::<Vec<_>>();
+                    let conftimes = handles
+                        .into_iter()
+                        .map(|handle| handle.join().unwrap())
+                        .collect::<Result<_, _>>()?;
+                    conftime_req.satisfy(conftimes)?
+                }
                 Request::Conftime(conftime_req) => {
                     let conftimes = conftime_req
                         .request()
@@ -211,7 +550,11 @@ impl WalletSync for EsploraBlockchain {
                         .request()
                         .map(|txid| {
                             let tx = tx_index.get(txid).expect("must be in index");
-                            Ok((tx.previous_outputs(), tx.to_tx()))
+                            #[cfg(feature = "elements")]
+                            check_confidential(tx, |script| {
+                                Ok(database.get_path_from_script_pubkey(script)?.is_some())
+                            })?;
+                            Ok((tx.previous_outputs(), self.url_client.cache_confirmed(tx)))
                         })
                         .collect::<Result<_, Error>>()?;
                     tx_req.satisfy(full_txs)?
@@ -220,179 +563,1719 @@ impl WalletSync for EsploraBlockchain {
             }
         };
 
-        database.commit_batch(batch_update)?;
+        self.pending_inputs.update(&tx_index);
 
-        Ok(())
+        Ok((batch_update, tx_index))
     }
-}
 
-impl UrlClient {
-    fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/tx/{}/raw", self.url, txid))
-            .call();
+    /// Return the number of HTTP requests sent since the client was created or the counter was
+    /// last reset, including retries and requests to the fallback URLs
+    ///
+    /// The counter is shared with the clients returned by `clone_with_concurrency`.
+    pub fn request_count(&self) -> u64 {
+        self.url_client.request_count.load(Ordering::Relaxed)
+    }
 
-        match resp {
-            Ok(resp) => Ok(Some(deserialize(&into_bytes(resp)?)?)),
-            Err(ureq::Error::Status(code, _)) => {
-                if is_status_not_found(code) {
-                    return Ok(None);
-                }
-                Err(EsploraError::HttpResponse(code))
+    /// Reset the counter returned by [`EsploraBlockchain::request_count`] to zero
+    pub fn reset_request_count(&self) {
+        self.url_client.request_count.store(0, Ordering::Relaxed);
+    }
+
+    /// Fetch the transactions with the given txids, in the same order
+    ///
+    /// Up to `concurrency` transactions are requested in parallel. Unknown txids are returned as
+    /// `None`.
+    pub fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>, Error> {
+        let mut txs = Vec::with_capacity(txids.len());
+        for chunk in txids.chunks((self.concurrency as usize).max(1)) {
+            let handles = chunk
+                .iter()
+                .map(|txid| {
+                    let client = self.url_client.clone();
+                    let txid = *txid;
+                    std::thread::spawn(move || client._get_tx(&txid))
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                txs.push(handle.join().unwrap()?);
             }
-            Err(e) => Err(EsploraError::Ureq(e)),
         }
+        Ok(txs)
     }
 
-    fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
-        match self._get_tx(txid) {
-            Ok(Some(tx)) => Ok(tx),
-            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
-            Err(e) => Err(e),
-        }
+    /// Fetch a transaction from its raw serialization, bypassing the transaction cache
+    ///
+    /// Unlike the verbose endpoint used by the cache, the transaction is returned exactly as
+    /// serialized by the server. Returns `None` if the transaction is unknown.
+    pub fn get_tx_raw(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(self.url_client._get_tx_raw(txid)?)
     }
 
-    fn _get_header(&self, block_height: u32) -> Result<BlockHeader, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/block-height/{}", self.url, block_height))
-            .call();
+    /// Fetch the hex serialization of a transaction without decoding it, `None` if the
+    /// transaction is unknown
+    pub fn get_tx_hex(&self, txid: &Txid) -> Result<Option<String>, Error> {
+        Ok(self.url_client._get_tx_hex(txid)?)
+    }
 
-        let bytes = match resp {
-            Ok(resp) => Ok(into_bytes(resp)?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
-            Err(e) => Err(EsploraError::Ureq(e)),
-        }?;
+    /// Fetch a transaction together with the outputs spent by each of its inputs, `None` for
+    /// coinbase inputs
+    ///
+    /// Returns `None` if the transaction is unknown.
+    pub fn get_tx_with_prevouts(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(Transaction, Vec<Option<TxOut>>)>, Error> {
+        Ok(self
+            .url_client
+            ._get_tx_info(txid)?
+            .map(|tx| (tx.to_tx(), tx.previous_outputs())))
+    }
 
-        let hash = std::str::from_utf8(&bytes)
-            .map_err(|_| EsploraError::HeaderHeightNotFound(block_height))?;
+    /// Get the fee in satoshis paid by a transaction, as reported by esplora, or `None` if the
+    /// transaction is unknown
+    pub fn get_tx_fee(&self, txid: &Txid) -> Result<Option<u64>, Error> {
+        Ok(self.url_client._get_tx_info(txid)?.map(|tx| tx.fee))
+    }
 
-        let resp = self
-            .agent
-            .get(&format!("{}/block/{}/header", self.url, hash))
-            .call();
+    /// Sync `database` incrementally, assuming it was fully synced at `since_height`
+    ///
+    /// Every known script is still requested, but the older pages of the histories, containing
+    /// only transactions confirmed below `since_height`, aren't fetched: the transactions
+    /// confirmed there are taken from `database` instead. To catch the reorgs that happened
+    /// after the last sync, the transactions in the `reorg_depth` blocks below `since_height` are
+    /// fetched again, so a reorg deeper than that can leave stale transactions in `database`.
+    ///
+    /// The scripts must already be cached in `database`, e.g. by a previous
+    /// [`Wallet::sync`](crate::wallet::Wallet::sync).
+    pub fn sync_from<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        since_height: u32,
+    ) -> Result<(), Error> {
+        self.check_network()?;
+        self.check_reorg(database)?;
+        let (batch_update, tx_index) = self.fetch_sync(database, Some(since_height))?;
+        self.notify_new_txs(database, &tx_index)?;
+        database.commit_batch(batch_update)
+    }
 
-        match resp {
-            Ok(resp) => Ok(deserialize(&Vec::from_hex(&resp.into_string()?)?)?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
-            Err(e) => Err(EsploraError::Ureq(e)),
+    /// Sync `database` like [`Wallet::sync`](crate::wallet::Wallet::sync), returning the txids
+    /// of the unconfirmed transactions that were dropped from the mempool of the service, e.g.
+    /// because they expired or were replaced
+    ///
+    /// Like in any sync, the dropped transactions are removed from `database`. A transaction
+    /// that's missing from the histories of the wallet but still known by the service isn't
+    /// reported.
+    pub fn sync_evicted<D: BatchDatabase>(&self, database: &mut D) -> Result<HashSet<Txid>, Error> {
+        self.check_network()?;
+        self.check_reorg(database)?;
+        let (batch_update, tx_index) = self.fetch_sync(database, None)?;
+        let mut evicted = HashSet::new();
+        for txid in missing_unconfirmed(database, &tx_index)? {
+            if self.url_client._get_tx_status(&txid)?.is_none() {
+                evicted.insert(txid);
+            }
         }
-    }
+        self.notify_new_txs(database, &tx_index)?;
+        database.commit_batch(batch_update)?;
 
-    fn _broadcast(&self, transaction: &Transaction) -> Result<(), EsploraError> {
-        let resp = self
-            .agent
-            .post(&format!("{}/tx", self.url))
-            .send_string(&serialize(transaction).to_hex());
+        Ok(evicted)
+    }
 
-        match resp {
-            Ok(_) => Ok(()), // We do not return the txid?
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
-            Err(e) => Err(EsploraError::Ureq(e)),
-        }
+    /// Sync the transactions of a fixed set of `scripts` into `database`, e.g. imported
+    /// addresses, without deriving any script nor applying the stop gap
+    ///
+    /// The whole history of every script is fetched, and its transactions are added to
+    /// `database` along with their unspent outputs, with the amounts received and sent computed
+    /// against `scripts` alone. Nothing is removed from `database`, while a regular sync drops
+    /// the transactions that don't involve the scripts of the wallet, so the scripts should be
+    /// synced into a database of their own.
+    pub fn sync_scripts<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        scripts: &[Script],
+    ) -> Result<(), Error> {
+        let scan = self.url_client._scan_keychain(
+            KeychainKind::External,
+            scripts,
+            None,
+            self.sync_concurrency(),
+            0,
+            None,
+            &self.cancellation_token,
+            None,
+        )?;
+        let batch_update = scripts_update(database, &scan.histories)?;
+        database.commit_batch(batch_update)
     }
 
-    fn _get_height(&self) -> Result<u32, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/blocks/tip/height", self.url))
-            .call();
+    /// Compute the changes a sync would apply to `database`, without writing to it
+    ///
+    /// The plan can be inspected before running the actual sync with
+    /// [`Wallet::sync`](crate::wallet::Wallet::sync). Reorgs are only detected and rolled back by
+    /// the actual sync.
+    pub fn plan_sync<D: BatchDatabase>(&self, database: &D) -> Result<SyncPlan, Error> {
+        self.check_network()?;
+        let (_, tx_index) = self.fetch_sync(database, None)?;
+        SyncPlan::new(
+            database,
+            tx_index.values().map(|tx| (tx.txid, tx.status.confirmed)),
+        )
+    }
 
-        match resp {
-            Ok(resp) => Ok(resp.into_string()?.parse()?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
-            Err(e) => Err(EsploraError::Ureq(e)),
-        }
+    /// Scan the scripts of both keychains in parallel, returning for each keychain the index of
+    /// the last script with transactions, or `None` if none has any
+    ///
+    /// Nothing is written to `database`, so that the indexes can be persisted separately.
+    pub fn scan_last_active_indexes<D: BatchDatabase>(
+        &self,
+        database: &D,
+    ) -> Result<HashMap<KeychainKind, Option<u32>>, Error> {
+        let scans = self.scan_keychains(database, 0)?;
+        Ok(scans
+            .into_iter()
+            .map(|(keychain, scan)| (keychain, scan.last_active_index))
+            .collect())
     }
 
-    fn _get_fee_estimates(&self) -> Result<HashMap<String, f64>, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/fee-estimates", self.url,))
-            .call();
+    /// Return the index of the last script with transactions of each keychain found by the last
+    /// sync, or `None` for a keychain without any
+    ///
+    /// The map is empty until a sync ran. Storing the indexes, e.g. with
+    /// [`BatchOperations::set_last_index`](crate::database::BatchOperations::set_last_index),
+    /// makes the following syncs scan at least up to them.
+    pub fn last_active_indexes(&self) -> HashMap<KeychainKind, Option<u32>> {
+        self.last_active_indexes.get()
+    }
 
-        let map = match resp {
-            Ok(resp) => {
-                let map: HashMap<String, f64> = resp.into_json()?;
-                Ok(map)
+    /// Fetch the whole transaction history of each script, in the same order
+    ///
+    /// Unlike a wallet sync, a failed request doesn't abort the others: every script gets its
+    /// own result so that only the failed ones have to be retried. Up to `concurrency` scripts
+    /// are requested in parallel.
+    pub fn try_sync_scripts(&self, scripts: &[Script]) -> Vec<Result<Vec<Tx>, Error>> {
+        let mut results = Vec::with_capacity(scripts.len());
+        for chunk in scripts.chunks((self.concurrency as usize).max(1)) {
+            let handles = chunk
+                .iter()
+                .map(|script| {
+                    let client = self.url_client.clone();
+                    let cancellation_token = self.cancellation_token.clone();
+                    let script = script.clone();
+                    std::thread::spawn(move || {
+                        client._scripthash_history(&script, 0, &cancellation_token)
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                results.push(handle.join().unwrap());
             }
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
-            Err(e) => Err(EsploraError::Ureq(e)),
-        }?;
+        }
+        results
+    }
 
-        Ok(map)
+    /// Get a page of the transaction history of `address`
+    ///
+    /// The first page contains the unconfirmed transactions and up to 25 confirmed ones. Older
+    /// transactions are returned 25 at a time by passing the txid of the last transaction of
+    /// the previous page as `last_seen`, until an empty page is returned.
+    pub fn get_address_txs(
+        &self,
+        address: &Address,
+        last_seen: Option<Txid>,
+    ) -> Result<Vec<Tx>, Error> {
+        Ok(self.url_client._address_txs(address, last_seen)?)
     }
 
-    fn _scripthash_txs(
+    /// Get a page of the transaction history of `script`, queried by its scripthash
+    ///
+    /// Pages work the same way as in [`EsploraBlockchain::get_address_txs`]. Esplora's scripthash
+    /// is the SHA256 of the script pubkey, hex encoded without reversing it as Electrum does.
+    pub fn get_scripthash_txs(
         &self,
         script: &Script,
         last_seen: Option<Txid>,
-    ) -> Result<Vec<Tx>, EsploraError> {
-        let script_hash = sha256::Hash::hash(script.as_bytes()).into_inner().to_hex();
-        let url = match last_seen {
-            Some(last_seen) => format!(
-                "{}/scripthash/{}/txs/chain/{}",
-                self.url, script_hash, last_seen
-            ),
-            None => format!("{}/scripthash/{}/txs", self.url, script_hash),
-        };
-        Ok(self.agent.get(&url).call()?.into_json()?)
+    ) -> Result<Vec<Tx>, Error> {
+        Ok(self.url_client._scripthash_txs(script, last_seen)?)
     }
-}
 
-fn is_status_not_found(status: u16) -> bool {
-    status == 404
-}
+    /// Get the unconfirmed transactions of `script`, without walking its confirmed history
+    ///
+    /// Uses the `/scripthash/:hash/txs/mempool` endpoint, equivalent to
+    /// `/address/:address/txs/mempool` for scripts without an address.
+    pub fn get_mempool_txs(&self, script: &Script) -> Result<Vec<Tx>, Error> {
+        Ok(self.url_client._scripthash_mempool_txs(script)?)
+    }
 
-fn into_bytes(resp: Response) -> Result<Vec<u8>, io::Error> {
-    const BYTES_LIMIT: usize = 10 * 1_024 * 1_024;
+    /// Get the chain and mempool statistics of the outputs funded and spent by `script`
+    pub fn get_address_stats(&self, script: &Script) -> Result<AddressStats, Error> {
+        Ok(self.url_client._get_address_stats(script)?)
+    }
 
-    let mut buf: Vec<u8> = vec![];
-    resp.into_reader()
-        .take((BYTES_LIMIT + 1) as u64)
-        .read_to_end(&mut buf)?;
-    if buf.len() > BYTES_LIMIT {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "response too big for into_bytes",
-        ));
+    /// Get the statistics of several scripts, as in [`EsploraBlockchain::get_address_stats`], in
+    /// the same order
+    ///
+    /// Up to `concurrency` scripts are requested in parallel.
+    pub fn get_balances(&self, scripts: &[Script]) -> Result<Vec<AddressStats>, Error> {
+        let mut stats = Vec::with_capacity(scripts.len());
+        for chunk in scripts.chunks((self.concurrency as usize).max(1)) {
+            let handles = chunk
+                .iter()
+                .map(|script| {
+                    let client = self.url_client.clone();
+                    let script = script.clone();
+                    std::thread::spawn(move || client._get_address_stats(&script))
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                stats.push(handle.join().unwrap()?);
+            }
+        }
+        Ok(stats)
     }
 
-    Ok(buf)
-}
+    /// Get the `limit` most recent transactions touching any script of `database`, unconfirmed
+    /// first and then from the highest block
+    ///
+    /// Only the pages of each history needed to reach `limit` transactions are fetched, up to
+    /// `concurrency` scripts in parallel. A transaction touching several scripts of the wallet is
+    /// returned once.
+    pub fn get_recent_activity<D: BatchDatabase>(
+        &self,
+        database: &D,
+        limit: usize,
+    ) -> Result<Vec<Tx>, Error> {
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let scripts = database.iter_script_pubkeys(None)?;
+        let mut histories = Vec::with_capacity(scripts.len());
+        for chunk in scripts.chunks((self.concurrency as usize).max(1)) {
+            let handles = chunk
+                .iter()
+                .map(|script| {
+                    let client = self.url_client.clone();
+                    let script = script.clone();
+                    std::thread::spawn(move || client._scripthash_recent(&script, limit))
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                histories.push(handle.join().unwrap()?);
+            }
+        }
+        Ok(recent_activity(histories, limit))
+    }
 
-impl ConfigurableBlockchain for EsploraBlockchain {
-    type Config = super::EsploraBlockchainConfig;
+    /// Get the [`MerkleProof`] of a confirmed transaction, or `None` if it's unknown or unconfirmed
+    pub fn get_merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, Error> {
+        Ok(self.url_client._get_merkle_proof(txid)?)
+    }
 
-    fn from_config(config: &Self::Config) -> Result<Self, Error> {
-        let mut agent_builder = ureq::AgentBuilder::new();
+    /// Verify that a transaction is included in the block the server reports it confirmed in
+    ///
+    /// The status, the header of the block and the merkle proof of the transaction are fetched
+    /// separately, and the merkle root computed from the proof is compared to the one in the
+    /// header. Fails with [`EsploraError::TransactionNotFound`] or
+    /// [`EsploraError::TransactionUnconfirmed`] if the transaction isn't in the chain.
+    pub fn verify_tx_inclusion(&self, txid: &Txid) -> Result<InclusionProof, Error> {
+        let (height, block_hash) = match self.url_client._get_tx_status(txid)? {
+            Some(TxStatus {
+                confirmed: true,
+                block_height: Some(height),
+                block_hash: Some(block_hash),
+                ..
+            }) => (height, block_hash),
+            Some(_) => return Err(EsploraError::TransactionUnconfirmed(*txid).into()),
+            None => return Err(EsploraError::TransactionNotFound(*txid).into()),
+        };
+        let header = self.url_client._get_header_by_hash(&block_hash)?;
+        let proof = self.url_client._get_merkle_proof(txid)?;
 
-        if let Some(timeout) = config.timeout {
-            agent_builder = agent_builder.timeout(Duration::from_secs(timeout));
+        Ok(InclusionProof::new(
+            txid,
+            height,
+            block_hash,
+            proof.as_ref(),
+            &header,
+        ))
+    }
+
+    /// Get the BIP37 [`MerkleBlock`] proving the inclusion of a confirmed transaction, or `None`
+    /// if it's unknown or unconfirmed
+    pub fn get_merkle_block(&self, txid: &Txid) -> Result<Option<MerkleBlock>, Error> {
+        Ok(self.url_client._get_merkle_block(txid)?)
+    }
+
+    /// Get the spending status of the output `vout` of transaction `txid`, or `None` if the
+    /// transaction is unknown
+    pub fn get_output_status(&self, txid: &Txid, vout: u32) -> Result<Option<OutputStatus>, Error> {
+        Ok(self.url_client._get_output_status(txid, vout)?)
+    }
+
+    /// Get the spending status of every output of transaction `txid`, in order, with a single
+    /// request
+    ///
+    /// Returns an empty list if the transaction is unknown.
+    pub fn get_tx_outspends(&self, txid: &Txid) -> Result<Vec<OutputStatus>, Error> {
+        Ok(self.url_client._get_tx_outspends(txid)?)
+    }
+
+    /// Get the transaction spending `outpoint`, or `None` if the output is unspent or its
+    /// transaction is unknown
+    pub fn get_spending_tx(&self, outpoint: &OutPoint) -> Result<Option<Transaction>, Error> {
+        let status = self
+            .url_client
+            ._get_output_status(&outpoint.txid, outpoint.vout)?;
+        match status.and_then(|status| status.txid) {
+            Some(txid) => Ok(self.url_client._get_tx(&txid)?),
+            None => Ok(None),
         }
+    }
 
-        if let Some(proxy) = &config.proxy {
-            agent_builder = agent_builder
-                .proxy(Proxy::new(proxy).map_err(|e| Error::Esplora(Box::new(e.into())))?);
+    /// Get the confirmation status of a transaction, or `None` if it's neither in the chain nor
+    /// in the mempool
+    pub fn get_tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>, Error> {
+        Ok(self.url_client._get_tx_status(txid)?)
+    }
+
+    /// Get the confirmation details of a transaction, or `None` if it's not confirmed
+    ///
+    /// The number of confirmations is computed against the current chain tip.
+    pub fn get_confirmation(&self, txid: &Txid) -> Result<Option<ConfirmationInfo>, Error> {
+        let status = match self.url_client._get_tx_status(txid)? {
+            Some(status) if status.confirmed => status,
+            _ => return Ok(None),
+        };
+        let tip_height = self.url_client._get_height()?;
+
+        Ok(ConfirmationInfo::new(&status, tip_height))
+    }
+
+    /// Return whether the transaction has at least `min_confs` confirmations, counted as in
+    /// [`ConfirmationInfo::confirmations`]
+    ///
+    /// Unconfirmed and unknown transactions have zero confirmations.
+    pub fn is_finalized(&self, txid: &Txid, min_confs: u32) -> Result<bool, Error> {
+        let confirmation = self.get_confirmation(txid)?;
+        Ok(confirmation.map_or(0, |info| info.confirmations) >= min_confs)
+    }
+
+    /// Return how long an unconfirmed transaction has been waiting in the mempool, `None` if
+    /// it's confirmed or unknown
+    ///
+    /// The age is counted from the time the server first saw the transaction if it reports it,
+    /// like the mempool.space backend does, otherwise from the first time it was looked up with
+    /// this method.
+    pub fn get_unconfirmed_age(&self, txid: &Txid) -> Result<Option<Duration>, Error> {
+        match self.url_client._get_tx_status(txid)? {
+            Some(status) if !status.confirmed => {}
+            _ => {
+                self.first_seen.forget(txid);
+                return Ok(None);
+            }
         }
+        let reported = self.url_client._get_first_seen(txid)?;
 
-        let mut blockchain = EsploraBlockchain::new(config.base_url.as_str(), config.stop_gap)
-            .with_agent(agent_builder.build());
+        Ok(Some(self.first_seen.age(*txid, reported, get_timestamp())))
+    }
 
-        if let Some(concurrency) = config.concurrency {
-            blockchain = blockchain.with_concurrency(concurrency);
+    /// Return whether the transaction with the given txid was replaced, i.e. it was evicted
+    /// from the mempool and one of its inputs is now spent by another transaction
+    ///
+    /// esplora forgets evicted transactions, so their inputs are looked up among the unconfirmed
+    /// transactions broadcast or synced by this client or its clones. Fails with
+    /// [`EsploraError::TransactionNotFound`] if the transaction is unknown to both the client and
+    /// the server, e.g. after a restart: use [`EsploraBlockchain::is_transaction_replaced`] in
+    /// that case.
+    pub fn is_tx_replaced(&self, txid: &Txid) -> Result<bool, Error> {
+        match self.pending_inputs.get(txid) {
+            Some(inputs) => self.inputs_replaced(txid, &inputs),
+            None if self.url_client._get_tx_status(txid)?.is_some() => Ok(false),
+            None => Err(EsploraError::TransactionNotFound(*txid).into()),
         }
+    }
 
-        Ok(blockchain)
+    /// Return whether `tx` was replaced, i.e. it was evicted from the mempool and one of its
+    /// inputs is now spent by another transaction
+    ///
+    /// Unlike [`EsploraBlockchain::is_tx_replaced`] this works for any transaction, since its
+    /// inputs don't have to be looked up.
+    pub fn is_transaction_replaced(&self, tx: &Transaction) -> Result<bool, Error> {
+        let inputs = tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect::<Vec<_>>();
+        self.inputs_replaced(&tx.txid(), &inputs)
     }
-}
 
-impl From<ureq::Error> for EsploraError {
-    fn from(e: ureq::Error) -> Self {
-        match e {
-            ureq::Error::Status(code, _) => EsploraError::HttpResponse(code),
-            e => EsploraError::Ureq(e),
+    fn inputs_replaced(&self, txid: &Txid, inputs: &[OutPoint]) -> Result<bool, Error> {
+        if let Some(status) = self.url_client._get_tx_status(txid)? {
+            if status.confirmed {
+                self.pending_inputs.forget(txid);
+            }
+            return Ok(false);
+        }
+
+        for previous_output in inputs {
+            let status = self
+                .url_client
+                ._get_output_status(&previous_output.txid, previous_output.vout)?;
+            if super::is_spent_by_other(txid, status.as_ref()) {
+                return Ok(true);
+            }
         }
+
+        Ok(false)
+    }
+
+    /// Check that the service is reachable, returning its tip height and the network it serves
+    ///
+    /// Useful to fail fast before a sync, e.g. if the url of a mainnet service is used with a
+    /// testnet wallet.
+    pub fn check_health(&self) -> Result<HealthInfo, Error> {
+        let tip_height = self.url_client._get_height()?;
+        let genesis_hash = self.url_client._get_block_hash(0)?;
+        Ok(HealthInfo::new(tip_height, genesis_hash))
+    }
+
+    /// Get the height of the chain tip
+    pub fn get_tip_height(&self) -> Result<u32, Error> {
+        Ok(self.url_client._get_height()?)
+    }
+
+    /// Get the hash of the genesis block of the chain served by the service
+    ///
+    /// Comparing it with [`genesis_block`](bitcoin::blockdata::constants::genesis_block) of the
+    /// network of the wallet detects a misconfigured url before syncing, see also
+    /// [`EsploraBlockchain::check_health`].
+    pub fn get_genesis_hash(&self) -> Result<BlockHash, Error> {
+        Ok(self.url_client._get_block_hash(0)?)
+    }
+
+    /// Get the hash of the chain tip
+    pub fn get_tip_hash(&self) -> Result<BlockHash, Error> {
+        Ok(self.url_client._get_tip_hash()?)
+    }
+
+    /// Broadcast a transaction given its hex serialization, e.g. as returned by
+    /// [`EsploraBlockchain::get_tx_hex`], without decoding it
+    ///
+    /// The server validates the transaction and rejects it with [`EsploraError::HttpResponse`]
+    /// if it's malformed or invalid.
+    pub fn broadcast_hex(&self, hex: &str) -> Result<(), Error> {
+        Ok(self.url_client._broadcast_hex(hex)?)
+    }
+
+    /// Broadcast `tx` concurrently to the primary url and to each of `extra_urls`, e.g. the
+    /// endpoints of other esplora operators, so that it propagates even if some are down
+    ///
+    /// The fallback urls aren't used. Returns the result of each endpoint, the primary first,
+    /// and fails with the first error only if no endpoint accepted the transaction.
+    pub fn broadcast_to_all(
+        &self,
+        tx: &Transaction,
+        extra_urls: &[String],
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let hex = serialize(tx).to_hex();
+        let urls = BaseUrls::new(self.url_client.urls.primary(), extra_urls).all("/tx");
+        let mut results = Vec::with_capacity(urls.len());
+        for chunk in urls.chunks((self.concurrency as usize).max(1)) {
+            let handles = chunk
+                .iter()
+                .map(|url| {
+                    let client = self.url_client.clone();
+                    let (url, hex) = (url.clone(), hex.clone());
+                    std::thread::spawn(move || client._broadcast_hex_to(&url, &hex))
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                results.push(handle.join().unwrap());
+            }
+        }
+        let results = accepted_by_any(results)?;
+        self.pending_inputs.record(tx);
+        Ok(results
+            .into_iter()
+            .map(|r| r.map_err(Error::from))
+            .collect())
+    }
+
+    /// Broadcast a package of transactions, e.g. a parent with a CPFP child, so that they're
+    /// evaluated together against the mempool policies
+    ///
+    /// `txs` must be sorted topologically, parents first. The package is submitted atomically:
+    /// either all the transactions are accepted or none is. If the server doesn't support
+    /// packages, the transactions are broadcast one by one instead, in order, which isn't
+    /// atomic: a parent below the minimum relay fee is rejected without its child, and an error
+    /// on a child leaves the parents already broadcast in the mempool.
+    pub fn broadcast_package(&self, txs: &[Transaction]) -> Result<(), Error> {
+        self.url_client._broadcast_package(txs)?;
+        txs.iter().for_each(|tx| self.pending_inputs.record(tx));
+        Ok(())
+    }
+
+    /// Broadcast `tx`, then poll its status every `poll_interval_ms` until the server reports it
+    /// in the mempool or in the chain
+    ///
+    /// Returns `false` if the transaction still isn't known after `max_wait_ms`, e.g. because it
+    /// was dropped after being accepted.
+    pub fn broadcast_and_confirm(
+        &self,
+        tx: &Transaction,
+        poll_interval_ms: u64,
+        max_wait_ms: u64,
+    ) -> Result<bool, Error> {
+        self.url_client._broadcast(tx)?;
+        self.pending_inputs.record(tx);
+
+        let txid = tx.txid();
+        let start = Instant::new();
+        let max_wait = Duration::from_millis(max_wait_ms);
+        loop {
+            if self.url_client._get_tx_status(&txid)?.is_some() {
+                return Ok(true);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= max_wait {
+                return Ok(false);
+            }
+            std::thread::sleep(Duration::from_millis(poll_interval_ms).min(max_wait - elapsed));
+        }
+    }
+
+    /// Poll the status of `txid` and the chain tip every `poll_interval_ms` until the
+    /// transaction has at least `min_confs` confirmations, returning its number of confirmations
+    ///
+    /// If `max_wait_ms` elapses first, the number of confirmations reached so far is returned,
+    /// `0` if the transaction is unconfirmed or unknown.
+    pub fn wait_for_confirmation(
+        &self,
+        txid: &Txid,
+        min_confs: u32,
+        poll_interval_ms: u64,
+        max_wait_ms: u64,
+    ) -> Result<u32, Error> {
+        let start = Instant::new();
+        let max_wait = Duration::from_millis(max_wait_ms);
+        loop {
+            let confirmations = match self.url_client._get_tx_status(txid)? {
+                Some(TxStatus {
+                    block_height: Some(height),
+                    ..
+                }) => (self.url_client._get_height()? + 1).saturating_sub(height),
+                _ => 0,
+            };
+            let elapsed = start.elapsed();
+            if confirmations >= min_confs || elapsed >= max_wait {
+                return Ok(confirmations);
+            }
+            std::thread::sleep(Duration::from_millis(poll_interval_ms).min(max_wait - elapsed));
+        }
+    }
+
+    /// Get the full block with the given hash, to verify its contents locally
+    pub fn get_block(&self, hash: BlockHash) -> Result<Block, Error> {
+        Ok(self.url_client._get_block(&hash)?)
+    }
+
+    /// Get the header of the block at the given height
+    pub fn get_block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        Ok(self.url_client._get_header(height)?)
+    }
+
+    /// Get the timestamp of the block at the given height, or `None` if the height is above the
+    /// tip
+    pub fn get_block_time(&self, height: u32) -> Result<Option<u64>, Error> {
+        Ok(self.url_client._get_block_time(height)?)
+    }
+
+    /// Get the summaries of the 10 blocks ending at `start_height`, from the newest, or of the
+    /// last 10 blocks if `start_height` is `None`
+    pub fn get_blocks(&self, start_height: Option<u32>) -> Result<Vec<BlockSummary>, Error> {
+        Ok(self.url_client._get_blocks(start_height)?)
+    }
+
+    /// Scan the blocks from `start_height` to the tip for transactions involving `scripts`,
+    /// using the BIP158 filters served at `/block/:hash/filter`
+    ///
+    /// Unlike a wallet sync, the scripts aren't sent to the server: each block filter is matched
+    /// locally, and the full block is only downloaded when it matches. Returns the confirmed
+    /// transactions paying to `scripts` or spending their outputs, with the time of their block.
+    /// Spends of outputs received before `start_height` aren't detected.
+    ///
+    /// Fails with [`EsploraError::BlockFiltersUnsupported`] if the server doesn't serve filters.
+    #[cfg(feature = "esplora-filters")]
+    pub fn sync_with_filters(
+        &self,
+        scripts: &[Script],
+        start_height: u32,
+    ) -> Result<Vec<(Transaction, BlockTime)>, Error> {
+        let scripts = scripts.iter().cloned().collect::<HashSet<_>>();
+        let mut owned = HashSet::new();
+        let mut txs = vec![];
+
+        let tip = self.url_client._get_height()?;
+        for height in start_height..=tip {
+            self.cancellation_token.check()?;
+            let hash = self.url_client._get_block_hash(height)?;
+            let filter = self.url_client._get_block_filter(&hash)?;
+            if !super::filter_matches(&filter, &hash, &scripts)? {
+                continue;
+            }
+
+            debug!("block filter match at height {}", height);
+            let block = self.url_client._get_block(&hash)?;
+            let block_time = BlockTime {
+                height,
+                timestamp: block.header.time as u64,
+            };
+            txs.extend(
+                super::relevant_txs(&block, &scripts, &mut owned)
+                    .into_iter()
+                    .map(|tx| (tx, block_time.clone())),
+            );
+        }
+
+        Ok(txs)
+    }
+
+    /// Estimate the fee rate for each of `targets`, resolved against a single fetch of the fee
+    /// estimates so that they're consistent with each other
+    pub fn estimate_fees(&self, targets: &[u16]) -> Result<HashMap<u16, FeeRate>, Error> {
+        let estimates = self.url_client._get_fee_estimates()?;
+        let mut fee_rates = super::into_fee_rates(targets, estimates)?;
+        for fee_rate in fee_rates.values_mut() {
+            *fee_rate = super::apply_fee_floor(*fee_rate, self.min_fee_rate);
+        }
+        Ok(fee_rates)
+    }
+
+    /// Get the fee rates of the high, medium and low priorities, estimated for confirmation
+    /// targets of 1, 6 and 144 blocks
+    pub fn get_fee_priorities(&self) -> Result<FeePriorities, Error> {
+        let fee_rates = self.estimate_fees(&FeePriorities::TARGETS)?;
+        Ok(FeePriorities::new(&fee_rates))
+    }
+
+    /// Get the mempool fee histogram as `(fee rate, cumulative vsize)` pairs, sorted by
+    /// descending fee rate
+    pub fn get_fee_histogram(&self) -> Result<Vec<(FeeRate, u64)>, Error> {
+        let mempool = self.url_client._get_mempool()?;
+        Ok(super::into_fee_histogram(mempool.fee_histogram))
+    }
+
+    /// Get the txids of all the transactions in the mempool of the service
+    ///
+    /// The list is unordered and large: each txid takes about 67 bytes of JSON, so a mempool of
+    /// 100,000 transactions is a response of almost 7 MB, which has to fit in the
+    /// `max_response_bytes` limit. Prefer [`EsploraBlockchain::get_tx_status`] to check a few
+    /// transactions.
+    pub fn get_mempool_txids(&self) -> Result<Vec<Txid>, Error> {
+        Ok(self.url_client._get_mempool_txids()?)
+    }
+}
+
+impl Blockchain for EsploraBlockchain {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![
+            Capability::FullHistory,
+            Capability::GetAnyTx,
+            Capability::AccurateFees,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        self.check_network()?;
+        self.url_client._broadcast(tx)?;
+        self.pending_inputs.record(tx);
+        Ok(())
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        self.check_network()?;
+        let estimates = self.url_client._get_fee_estimates()?;
+        let fee_rate = super::into_fee_rate(target, estimates)?;
+        Ok(super::apply_fee_floor(fee_rate, self.min_fee_rate))
+    }
+}
+
+impl Deref for EsploraBlockchain {
+    type Target = UrlClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.url_client
+    }
+}
+
+impl StatelessBlockchain for EsploraBlockchain {}
+
+impl GetHeight for EsploraBlockchain {
+    fn get_height(&self) -> Result<u32, Error> {
+        self.check_network()?;
+        Ok(self.url_client._get_height()?)
+    }
+}
+
+impl GetTx for EsploraBlockchain {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        self.check_network()?;
+        Ok(self.url_client._get_tx(txid)?)
+    }
+}
+
+impl GetBlockHash for EsploraBlockchain {
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        self.check_network()?;
+        Ok(self.url_client._get_block_hash(height as u32)?)
+    }
+}
+
+impl WalletSync for EsploraBlockchain {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        _progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        self.check_network()?;
+        self.check_reorg(database)?;
+        let (batch_update, tx_index) = self.fetch_sync(database, None)?;
+        self.notify_new_txs(database, &tx_index)?;
+        database.commit_batch(batch_update)?;
+
+        Ok(())
+    }
+}
+
+impl super::EsploraApi for EsploraBlockchain {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        GetTx::get_tx(self, txid)
+    }
+
+    fn get_tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>, Error> {
+        EsploraBlockchain::get_tx_status(self, txid)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        Blockchain::broadcast(self, tx)
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        Blockchain::estimate_fee(self, target)
+    }
+
+    fn get_tip_height(&self) -> Result<u32, Error> {
+        GetHeight::get_height(self)
+    }
+}
+
+impl UrlClient {
+    fn throttle(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            std::thread::sleep(rate_limiter.acquire());
+        }
+    }
+
+    fn request(&self, method: &str, url: &str, timeout: Option<u64>) -> Request {
+        self.request_count.fetch_add(1, Ordering::Relaxed);
+        let mut req = self.headers.iter().fold(
+            self.agent
+                .request(method, url)
+                .set("Accept", accept_header(url)),
+            |req, (name, value)| req.set(name, value),
+        );
+        if self.gzip {
+            req = req.set("Accept-Encoding", "gzip, deflate");
+        }
+        match timeout {
+            Some(timeout) => req.timeout(Duration::from_secs(timeout)),
+            None => req,
+        }
+    }
+
+    /// Build a `method` request to `url` and send it with `send`, notifying the observer
+    fn call<F>(
+        &self,
+        method: &str,
+        url: &str,
+        timeout: Option<u64>,
+        send: F,
+    ) -> Result<Response, ureq::Error>
+    where
+        F: FnOnce(Request) -> Result<Response, ureq::Error>,
+    {
+        let req = self.request(method, url, timeout);
+        let observer = match &self.observer {
+            Some(observer) => observer,
+            None => return send(req),
+        };
+
+        observer.0.on_request(method, url);
+        let start = Instant::new();
+        let resp = send(req);
+        let status = match &resp {
+            Ok(resp) => Some(resp.status()),
+            Err(ureq::Error::Status(code, _)) => Some(*code),
+            Err(_) => None,
+        };
+        observer.0.on_response(url, status, start.elapsed());
+        resp
+    }
+
+    /// Send a request to `path` on each base url in turn, until one of them doesn't fail with a
+    /// transport or server error
+    fn failover<F>(&self, path: &str, send: F) -> Result<Response, ureq::Error>
+    where
+        F: Fn(&str) -> Result<Response, ureq::Error>,
+    {
+        let mut last_resp = None;
+        for index in self.urls.order() {
+            let url = self.urls.url(index, path);
+            let resp = send(&url);
+            if !is_server_failure(&resp) {
+                self.urls.mark_healthy(index);
+                return resp;
+            }
+            debug!("request to {} failed, trying the next url", url);
+            last_resp = Some(resp);
+        }
+        last_resp.expect("there is always at least one url")
+    }
+
+    fn get(&self, path: &str) -> Result<Response, ureq::Error> {
+        self.get_with_timeout(path, self.timeouts.get)
+    }
+
+    fn get_with_timeout(&self, path: &str, timeout: Option<u64>) -> Result<Response, ureq::Error> {
+        self.failover(path, |url| self.get_with_retry(url, timeout))
+    }
+
+    fn get_with_retry(&self, url: &str, timeout: Option<u64>) -> Result<Response, ureq::Error> {
+        let mut attempt = 0;
+        loop {
+            self.throttle();
+            let resp = self.call("GET", url, timeout, Request::call);
+            if let Some(retry) = &self.retry {
+                let delay = match &resp {
+                    Err(ureq::Error::Status(429, resp)) => {
+                        Some(retry.rate_limited_delay(attempt, resp.header("Retry-After")))
+                    }
+                    resp if is_server_failure(resp) => Some(retry.delay(attempt)),
+                    _ => None,
+                };
+                if let (Some(delay), true) = (delay, attempt < retry.max_retries) {
+                    debug!("retrying request to {} (attempt {})", url, attempt + 1);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                    continue;
+                }
+            }
+            return resp;
+        }
+    }
+
+    /// Convert `tx` to a [`Transaction`], caching it if it's confirmed
+    fn cache_confirmed(&self, tx: &Tx) -> Transaction {
+        let transaction = tx.to_tx();
+        if let (Some(tx_cache), true) = (&self.tx_cache, tx.status.confirmed) {
+            tx_cache.insert(tx.txid, transaction.clone());
+        }
+        transaction
+    }
+
+    fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
+        if let Some(tx_cache) = &self.tx_cache {
+            if let Some(tx) = tx_cache.get(txid) {
+                return Ok(Some(tx));
+            }
+            // the verbose endpoint also returns the status, telling whether the tx can be cached
+            let tx = self._get_tx_info(txid)?;
+            return Ok(tx.map(|tx| self.cache_confirmed(&tx)));
+        }
+
+        self._get_tx_raw(txid)
+    }
+
+    fn _get_tx_raw(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/raw", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(deserialize(&into_bytes(
+                resp,
+                self.max_response_bytes,
+            )?)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
+        match self._get_tx(txid) {
+            Ok(Some(tx)) => Ok(tx),
+            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn _get_tx_info(&self, txid: &Txid) -> Result<Option<Tx>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(into_json(resp, self.max_response_bytes)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Return `conftime` if the merkle proof of `txid` is valid, `None` otherwise
+    fn _verified_conftime(
+        &self,
+        txid: &Txid,
+        conftime: Option<BlockTime>,
+    ) -> Result<Option<BlockTime>, EsploraError> {
+        let conftime = match conftime {
+            Some(conftime) => conftime,
+            None => return Ok(None),
+        };
+
+        let verified = match self._get_merkle_proof(txid)? {
+            Some(proof) => {
+                let header = self._get_header(conftime.height)?;
+                super::verify_inclusion(txid, conftime.height, &proof, &header)
+            }
+            None => false,
+        };
+        if verified {
+            Ok(Some(conftime))
+        } else {
+            warn!(
+                "invalid merkle proof for {} at height {}, treating it as unconfirmed",
+                txid, conftime.height
+            );
+            Ok(None)
+        }
+    }
+
+    fn _get_merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/merkle-proof", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(into_json(resp, self.max_response_bytes)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/status", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(into_json(resp, self.max_response_bytes)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetch the time at which the server first saw `txid` in its mempool, `None` if the server
+    /// doesn't track it
+    fn _get_first_seen(&self, txid: &Txid) -> Result<Option<u64>, EsploraError> {
+        let resp = self.get(&format!("/v1/transaction-times?txId[]={}", txid));
+
+        match resp {
+            Ok(resp) => {
+                let times: Vec<u64> = into_json(resp, self.max_response_bytes)?;
+                // unknown transactions are reported as seen at time zero
+                Ok(times.into_iter().next().filter(|time| *time > 0))
+            }
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_tx_hex(&self, txid: &Txid) -> Result<Option<String>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/hex", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(into_string(resp, self.max_response_bytes)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_merkle_block(&self, txid: &Txid) -> Result<Option<MerkleBlock>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/merkleblock-proof", txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(deserialize(&Vec::from_hex(&into_string(
+                resp,
+                self.max_response_bytes,
+            )?)?)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_output_status(
+        &self,
+        txid: &Txid,
+        vout: u32,
+    ) -> Result<Option<OutputStatus>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/outspend/{}", txid, vout));
+
+        match resp {
+            Ok(resp) => Ok(Some(into_json(resp, self.max_response_bytes)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_tx_outspends(&self, txid: &Txid) -> Result<Vec<OutputStatus>, EsploraError> {
+        let resp = self.get(&format!("/tx/{}/outspends", txid));
+
+        match resp {
+            Ok(resp) => Ok(into_json(resp, self.max_response_bytes)?),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_block_hash(&self, block_height: u32) -> Result<BlockHash, EsploraError> {
+        if let Some(hash) = self.block_hashes.get(block_height) {
+            return Ok(hash);
+        }
+        let resp = self.get(&format!("/block-height/{}", block_height));
+
+        let bytes = match resp {
+            Ok(resp) => Ok(into_bytes(resp, self.max_response_bytes)?),
+            Err(e) => Err(EsploraError::from(e)),
+        }?;
+
+        let hash = std::str::from_utf8(&bytes)
+            .map_err(|_| EsploraError::HeaderHeightNotFound(block_height))?;
+        let hash = BlockHash::from_hex(hash)?;
+        self.block_hashes.insert(block_height, hash);
+
+        Ok(hash)
+    }
+
+    fn _get_header(&self, block_height: u32) -> Result<BlockHeader, EsploraError> {
+        let hash = self._get_block_hash(block_height)?;
+        self._get_header_by_hash(&hash)
+    }
+
+    fn _get_header_by_hash(&self, hash: &BlockHash) -> Result<BlockHeader, EsploraError> {
+        let resp = self.get(&format!("/block/{}/header", hash));
+
+        let header = match resp {
+            Ok(resp) => deserialize(&Vec::from_hex(&into_string(
+                resp,
+                self.max_response_bytes,
+            )?)?)?,
+            Err(e) => return Err(e.into()),
+        };
+
+        checked_pow(header, self.verify_header_pow)
+    }
+
+    fn _get_block_time(&self, block_height: u32) -> Result<Option<u64>, EsploraError> {
+        let hash = match self._get_block_hash(block_height) {
+            Ok(hash) => hash,
+            Err(EsploraError::HttpResponse { status, .. }) if is_status_not_found(status) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let resp = self.get(&format!("/block/{}", hash));
+
+        match resp {
+            Ok(resp) => Ok(Some(
+                into_json::<BlockInfo>(resp, self.max_response_bytes)?.timestamp,
+            )),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_blocks(&self, start_height: Option<u32>) -> Result<Vec<BlockSummary>, EsploraError> {
+        let url = match start_height {
+            Some(height) => format!("/blocks/{}", height),
+            None => "/blocks".to_string(),
+        };
+        Ok(into_json(self.get(&url)?, self.max_response_bytes)?)
+    }
+
+    #[cfg(feature = "esplora-filters")]
+    fn _get_block_filter(&self, hash: &BlockHash) -> Result<BlockFilter, EsploraError> {
+        let resp = self.get(&format!("/block/{}/filter", hash));
+
+        match resp {
+            Ok(resp) => Ok(BlockFilter::new(&Vec::from_hex(&into_string(
+                resp,
+                self.max_response_bytes,
+            )?)?)),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => {
+                Err(EsploraError::BlockFiltersUnsupported)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_block(&self, hash: &BlockHash) -> Result<Block, EsploraError> {
+        let resp = self.get(&format!("/block/{}/raw", hash));
+
+        match resp {
+            Ok(resp) => Ok(deserialize(&into_bytes(resp, self.max_response_bytes)?)?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _broadcast(&self, transaction: &Transaction) -> Result<(), EsploraError> {
+        self._broadcast_hex(&serialize(transaction).to_hex())
+    }
+
+    fn _broadcast_hex(&self, hex: &str) -> Result<(), EsploraError> {
+        let resp = self.failover("/tx", |url| {
+            self.throttle();
+            self.call("POST", url, self.timeouts.broadcast, |req| {
+                req.send_string(hex)
+            })
+        });
+
+        let result = match resp {
+            Ok(_) => Ok(()), // We do not return the txid?
+            Err(e) => Err(e.into()),
+        };
+        ignore_already_known(result, self.strict_broadcast)
+    }
+
+    /// Broadcast to the `/tx` endpoint `url`, without failing over
+    fn _broadcast_hex_to(&self, url: &str, hex: &str) -> Result<(), EsploraError> {
+        self.throttle();
+        let resp = self.call("POST", url, self.timeouts.broadcast, |req| {
+            req.send_string(hex)
+        });
+        let result = resp.map(|_| ()).map_err(EsploraError::from);
+        ignore_already_known(result, self.strict_broadcast)
+    }
+
+    fn _broadcast_package(&self, txs: &[Transaction]) -> Result<(), EsploraError> {
+        let hexes: Vec<String> = txs.iter().map(|tx| serialize(tx).to_hex()).collect();
+        let resp = self.failover("/txs/package", |url| {
+            self.throttle();
+            self.call("POST", url, self.timeouts.broadcast, |req| {
+                req.send_json(serde_json::json!(hexes))
+            })
+        });
+
+        match resp {
+            Ok(_) => Ok(()),
+            // packages aren't supported by the server, fall back to broadcasting one at a time
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => {
+                hexes.iter().try_for_each(|hex| self._broadcast_hex(hex))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_height(&self) -> Result<u32, EsploraError> {
+        let resp = self.get("/blocks/tip/height");
+
+        let height = match resp {
+            Ok(resp) => into_string(resp, self.max_response_bytes)?.parse()?,
+            Err(e) => return Err(e.into()),
+        };
+        self.block_hashes.set_tip(height);
+
+        Ok(height)
+    }
+
+    fn _get_tip_hash(&self) -> Result<BlockHash, EsploraError> {
+        let resp = self.get("/blocks/tip/hash");
+
+        match resp {
+            Ok(resp) => Ok(BlockHash::from_hex(&into_string(
+                resp,
+                self.max_response_bytes,
+            )?)?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_fee_estimates(&self) -> Result<HashMap<String, f64>, EsploraError> {
+        let resp = self.get_with_timeout("/fee-estimates", self.timeouts.estimate_fee);
+
+        let map = match resp {
+            Ok(resp) => {
+                let map: HashMap<String, f64> = into_json(resp, self.max_response_bytes)?;
+                Ok(map)
+            }
+            Err(e) => Err(EsploraError::from(e)),
+        }?;
+
+        Ok(map)
+    }
+
+    fn _get_mempool(&self) -> Result<MempoolInfo, EsploraError> {
+        let resp = self.get("/mempool");
+
+        match resp {
+            Ok(resp) => Ok(into_json(resp, self.max_response_bytes)?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_mempool_txids(&self) -> Result<Vec<Txid>, EsploraError> {
+        let resp = self.get("/mempool/txids");
+
+        match resp {
+            Ok(resp) => Ok(into_json(resp, self.max_response_bytes)?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _get_address_stats(&self, script: &Script) -> Result<AddressStats, EsploraError> {
+        let resp = self.get(&format!("/scripthash/{}", script_hash(script)));
+
+        match resp {
+            Ok(resp) => Ok(into_json(resp, self.max_response_bytes)?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _scripthash_txs(
+        &self,
+        script: &Script,
+        last_seen: Option<Txid>,
+    ) -> Result<Vec<Tx>, EsploraError> {
+        self._txs(&format!("scripthash/{}", script_hash(script)), last_seen)
+    }
+
+    /// Fetch the history of `script`, stopping at the first page reaching below `min_height`
+    fn _scripthash_history(
+        &self,
+        script: &Script,
+        min_height: u32,
+        cancellation_token: &CancellationToken,
+    ) -> Result<Vec<Tx>, Error> {
+        let mut related_txs: Vec<Tx> = self._scripthash_txs(script, None)?;
+
+        let n_confirmed = related_txs.iter().filter(|tx| tx.status.confirmed).count();
+        // esplora pages on 25 confirmed transactions. If there's 25 or more we
+        // keep requesting to see if there's more.
+        if n_confirmed >= 25 {
+            loop {
+                cancellation_token.check()?;
+                if reached_height(&related_txs, min_height) {
+                    break;
+                }
+                let new_related_txs: Vec<Tx> =
+                    self._scripthash_txs(script, Some(related_txs.last().unwrap().txid))?;
+                let n = new_related_txs.len();
+                related_txs.extend(new_related_txs);
+                // we've reached the end
+                if n < 25 {
+                    break;
+                }
+            }
+        }
+        Ok(related_txs)
+    }
+
+    /// Fetch the pages of the history of `script` until it has at least `limit` transactions
+    fn _scripthash_recent(&self, script: &Script, limit: usize) -> Result<Vec<Tx>, EsploraError> {
+        let mut related_txs: Vec<Tx> = self._scripthash_txs(script, None)?;
+
+        let n_confirmed = related_txs.iter().filter(|tx| tx.status.confirmed).count();
+        if n_confirmed >= 25 {
+            while related_txs.len() < limit {
+                let new_related_txs: Vec<Tx> =
+                    self._scripthash_txs(script, Some(related_txs.last().unwrap().txid))?;
+                let n = new_related_txs.len();
+                related_txs.extend(new_related_txs);
+                if n < 25 {
+                    break;
+                }
+            }
+        }
+        Ok(related_txs)
+    }
+
+    /// Fetch the history of each of `scripts` of `keychain`, `concurrency` at a time, until
+    /// `limit` is reached
+    fn _scan_keychain(
+        &self,
+        keychain: KeychainKind,
+        scripts: &[Script],
+        limit: Option<GapLimit>,
+        concurrency: usize,
+        min_height: u32,
+        script_filter: Option<&ScriptFilter>,
+        cancellation_token: &CancellationToken,
+        sync_progress: Option<&ProgressCallback>,
+    ) -> Result<KeychainScan, Error> {
+        let mut scan = KeychainScan::default();
+        let mut txs_found = HashMap::new();
+        for batch in scripts.chunks(concurrency) {
+            if scan.reached(limit.as_ref()) {
+                break;
+            }
+            cancellation_token.check()?;
+            let handles = batch
+                .iter()
+                .cloned()
+                .map(|script| {
+                    let client = self.clone();
+                    let cancellation_token = cancellation_token.clone();
+                    // make each request in its own thread.
+                    std::thread::spawn(move || {
+                        client._scripthash_history(&script, min_height, &cancellation_token)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut txs_per_script: Vec<Vec<Tx>> = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<_, _>>()?;
+            if let Some(script_filter) = script_filter {
+                txs_per_script
+                    .iter_mut()
+                    .for_each(|txs| script_filter.retain(txs));
+            }
+
+            if let Some(sync_progress) = sync_progress {
+                let tx_counts = txs_per_script.iter().map(Vec::len);
+                sync_progress.report(&mut txs_found, scan.progress(keychain), tx_counts);
+            }
+            for (script, txs) in batch.iter().zip(txs_per_script) {
+                scan.push(script.clone(), txs);
+            }
+        }
+
+        Ok(scan)
+    }
+
+    fn _scripthash_mempool_txs(&self, script: &Script) -> Result<Vec<Tx>, EsploraError> {
+        let url = format!("/scripthash/{}/txs/mempool", script_hash(script));
+        Ok(into_json(self.get(&url)?, self.max_response_bytes)?)
+    }
+
+    fn _address_txs(
+        &self,
+        address: &Address,
+        last_seen: Option<Txid>,
+    ) -> Result<Vec<Tx>, EsploraError> {
+        self._txs(&format!("address/{}", address), last_seen)
+    }
+
+    fn _txs(&self, resource: &str, last_seen: Option<Txid>) -> Result<Vec<Tx>, EsploraError> {
+        let url = match last_seen {
+            Some(last_seen) => format!("/{}/txs/chain/{}", resource, last_seen),
+            None => format!("/{}/txs", resource),
+        };
+        Ok(into_json(self.get(&url)?, self.max_response_bytes)?)
+    }
+}
+
+fn is_status_not_found(status: u16) -> bool {
+    status == 404
+}
+
+fn is_server_failure(resp: &Result<Response, ureq::Error>) -> bool {
+    match resp {
+        Ok(_) => false,
+        Err(ureq::Error::Status(code, _)) => *code >= 500,
+        Err(ureq::Error::Transport(_)) => true,
+    }
+}
+
+fn check_header(name: &str, value: &str) -> Result<(), EsploraError> {
+    const NAME_SYMBOLS: &[u8] = b"!#$%&'*+-.^_`|~";
+
+    let valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || NAME_SYMBOLS.contains(&b));
+    let valid_value = value
+        .bytes()
+        .all(|b| b == b'\t' || (b' '..=b'~').contains(&b));
+
+    if valid_name && valid_value {
+        Ok(())
+    } else {
+        Err(EsploraError::InvalidHttpHeader(name.to_string()))
+    }
+}
+
+/// Maximum size of a response body when `max_response_bytes` isn't set
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 10 * 1_024 * 1_024;
+
+fn into_bytes(resp: Response, limit: Option<usize>) -> Result<Vec<u8>, EsploraError> {
+    if is_html(resp.content_type()) {
+        return Err(EsploraError::HtmlResponse);
+    }
+    read_body(resp, limit)
+}
+
+/// Read the body of `resp` whatever its content type, e.g. the message of an error response
+fn read_body(resp: Response, limit: Option<usize>) -> Result<Vec<u8>, EsploraError> {
+    let encoding = resp.header("Content-Encoding").map(str::to_ascii_lowercase);
+    decode_body(
+        resp.into_reader(),
+        encoding.as_deref(),
+        limit.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+    )
+}
+
+fn into_json<T: DeserializeOwned>(resp: Response, limit: Option<usize>) -> Result<T, EsploraError> {
+    serde_json::from_slice(&into_bytes(resp, limit)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+fn into_string(resp: Response, limit: Option<usize>) -> Result<String, EsploraError> {
+    String::from_utf8(into_bytes(resp, limit)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+/// Read a response body, decompressing it according to its `Content-Encoding`, and fail if it's
+/// larger than `limit` bytes once decompressed
+fn decode_body<'a, R: Read + 'a>(
+    reader: R,
+    encoding: Option<&str>,
+    limit: usize,
+) -> Result<Vec<u8>, EsploraError> {
+    let reader: Box<dyn Read + 'a> = match encoding {
+        Some("gzip") => Box::new(GzDecoder::new(reader)),
+        Some("deflate") => Box::new(ZlibDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+
+    let mut buf: Vec<u8> = vec![];
+    reader
+        .take(limit.saturating_add(1) as u64)
+        .read_to_end(&mut buf)?;
+    if buf.len() > limit {
+        return Err(EsploraError::ResponseTooLarge(limit));
+    }
+
+    Ok(buf)
+}
+
+impl ConfigurableBlockchain for EsploraBlockchain {
+    type Config = super::EsploraBlockchainConfig;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        if config.pinned_cert_der.is_some() {
+            return Err(EsploraError::CertificatePinningUnsupported.into());
+        }
+
+        let mut agent_builder = ureq::AgentBuilder::new();
+
+        if config.sequential {
+            agent_builder = agent_builder.max_idle_connections_per_host(1);
+        }
+
+        if let Some(timeout) = config.timeout {
+            agent_builder = agent_builder.timeout(Duration::from_secs(timeout));
+        }
+
+        if let Some(connect_timeout) = config.connect_timeout_secs {
+            agent_builder = agent_builder.timeout_connect(Duration::from_secs(connect_timeout));
+        }
+
+        let proxies = config.proxy_config()?;
+        if let Some(proxy) = proxies.as_ref().and_then(|p| p.for_url(&config.base_url)) {
+            agent_builder = agent_builder
+                .proxy(Proxy::new(proxy).map_err(|e| Error::Esplora(Box::new(e.into())))?);
+        }
+
+        let mut blockchain = EsploraBlockchain::new(config.base_url.as_str(), config.stop_gap)
+            .with_agent(agent_builder.build())
+            .with_fallback_urls(&config.fallback_urls);
+
+        if let Some(concurrency) = config.concurrency {
+            blockchain.concurrency = super::checked_concurrency(concurrency)?;
+        }
+        if config.sequential {
+            blockchain.concurrency = 1;
+        }
+
+        if let Some(reorg_depth) = config.reorg_depth {
+            blockchain = blockchain.with_reorg_depth(reorg_depth);
+        }
+
+        blockchain = blockchain.with_verify_merkle_proofs(config.verify_merkle_proofs);
+        blockchain.deterministic = config.deterministic;
+        blockchain.script_filter = config.script_filter;
+        if let Some(stop_gaps) = &config.stop_gaps {
+            blockchain.stop_gaps = stop_gaps.clone();
+        }
+        blockchain.gap_overshoot = config.gap_overshoot;
+        blockchain.min_fee_rate = config.min_fee_rate;
+
+        blockchain.url_client.retry = config.retry.clone();
+        blockchain.url_client.timeouts = Timeouts::from_config(config);
+        blockchain.url_client.gzip = config.gzip;
+        blockchain.url_client.max_response_bytes = config.max_response_bytes;
+        blockchain.url_client.strict_broadcast = config.strict_broadcast;
+        blockchain.url_client.verify_header_pow = config.verify_header_pow;
+        blockchain.network_check = config
+            .network
+            .map(|network| Arc::new(NetworkCheck::new(network)));
+        blockchain.url_client.rate_limiter = config
+            .max_requests_per_second
+            .map(|rps| Arc::new(RateLimiter::new(rps)));
+        blockchain.url_client.tx_cache = config.tx_cache_size.and_then(TxCache::new).map(Arc::new);
+
+        if let Some(headers) = &config.headers {
+            for (name, value) in headers {
+                check_header(name, value)?;
+                blockchain
+                    .url_client
+                    .headers
+                    .push((name.clone(), value.clone()));
+            }
+        }
+
+        Ok(blockchain)
+    }
+}
+
+impl From<ureq::Error> for EsploraError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, resp) => EsploraError::HttpResponse {
+                status,
+                message: read_body(resp, None)
+                    .map(|body| String::from_utf8_lossy(&body).into_owned())
+                    .unwrap_or_default(),
+            },
+            e => EsploraError::Ureq(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn decode_compressed_body() {
+        let status = br#"{"confirmed":true,"block_height":723412,"block_time":1645187412}"#;
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(status).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(status).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        for (body, encoding) in &[
+            (gzip, Some("gzip")),
+            (deflate, Some("deflate")),
+            (status.to_vec(), None),
+        ] {
+            let decoded = decode_body(&body[..], *encoding, status.len()).unwrap();
+            let status: TxStatus = serde_json::from_slice(&decoded).unwrap();
+            assert_eq!(
+                status,
+                TxStatus {
+                    confirmed: true,
+                    block_height: Some(723412),
+                    block_hash: None,
+                    block_time: Some(1645187412),
+                }
+            );
+        }
+
+        assert!(decode_body(&b"not gzip"[..], Some("gzip"), DEFAULT_MAX_RESPONSE_BYTES).is_err());
+        assert!(matches!(
+            decode_body(&status[..], None, status.len() - 1),
+            Err(EsploraError::ResponseTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn block_time_above_tip() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = vec![0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 15\r\nConnection: close\r\n\r\nBlock not found",
+                );
+            }
+        });
+
+        let blockchain = EsploraBlockchain::new(&url, 20);
+        assert_eq!(blockchain.get_block_time(1_000_000).unwrap(), None);
+        assert!(matches!(
+            blockchain.url_client._get_block_hash(1_000_000),
+            Err(EsploraError::HttpResponse { status: 404, message }) if message == "Block not found"
+        ));
+    }
+
+    #[test]
+    fn tx_replaced_by_txid() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::{TxIn, Witness};
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        // the replaced transaction was evicted and its input is spent by another one
+        let replacement = Txid::from_inner([3; 32]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = vec![0; 1024];
+                let len = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..len]).to_string();
+                let response = if request.contains("/outspend/") {
+                    let body = format!(r#"{{"spent":true,"txid":"{}","vin":0}}"#, replacement);
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([2; 32]), 0),
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFD,
+                witness: Witness::default(),
+            }],
+            output: vec![],
+        };
+        let blockchain = EsploraBlockchain::new(&url, 20);
+        assert!(matches!(
+            blockchain.is_tx_replaced(&tx.txid()),
+            Err(Error::Esplora(e)) if matches!(*e, EsploraError::TransactionNotFound(_))
+        ));
+        assert!(blockchain.is_transaction_replaced(&tx).unwrap());
+
+        // the inputs of the transactions broadcast or synced by the client are remembered
+        blockchain.pending_inputs.record(&tx);
+        assert!(blockchain.is_tx_replaced(&tx.txid()).unwrap());
     }
 }
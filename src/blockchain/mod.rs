@@ -35,6 +35,7 @@ use crate::{FeeRate, KeychainKind};
     feature = "rpc"
 ))]
 pub mod any;
+pub mod offline;
 mod script_sync;
 
 #[cfg(any(
@@ -44,6 +45,7 @@ mod script_sync;
     feature = "rpc"
 ))]
 pub use any::{AnyBlockchain, AnyBlockchainConfig};
+pub use self::offline::OfflineBlockchain;
 
 #[cfg(feature = "electrum")]
 #[cfg_attr(docsrs, doc(cfg(feature = "electrum")))]
@@ -0,0 +1,160 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Offline backend
+//!
+//! This module provides [`OfflineBlockchain`], a backend that never connects to the network and
+//! serves the data stored in a [`Database`] instead. It's useful to display the last synced state
+//! of a wallet while disconnected, switching back to a network backend once online.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::OfflineBlockchain;
+//! # use bdk::database::MemoryDatabase;
+//! # use bdk::FeeRate;
+//! let blockchain = OfflineBlockchain::new(MemoryDatabase::new())
+//!     .with_fee_rate(FeeRate::from_sat_per_vb(2.0));
+//! ```
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use bitcoin::{BlockHash, Transaction, Txid};
+
+use crate::blockchain::*;
+use crate::database::{BatchDatabase, Database};
+use crate::error::Error;
+use crate::FeeRate;
+
+/// Backend serving the data of a [`Database`] without any network access
+///
+/// Broadcasting, and any call needing data that isn't stored locally, fails with
+/// [`Error::Offline`]. Syncing is a no-op, leaving the last synced state of the wallet untouched.
+#[derive(Debug)]
+pub struct OfflineBlockchain<D> {
+    database: Mutex<D>,
+    fee_rate: Option<FeeRate>,
+}
+
+impl<D: Database> OfflineBlockchain<D> {
+    /// Create a new instance serving the data stored in `database`
+    ///
+    /// `database` should hold the same data as the database of the wallet, e.g. another handle
+    /// to the same `sled` tree or `sqlite` file.
+    pub fn new(database: D) -> Self {
+        OfflineBlockchain {
+            database: Mutex::new(database),
+            fee_rate: None,
+        }
+    }
+
+    /// Set the fee rate returned by the fee estimation, e.g. the last one fetched while online
+    pub fn with_fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_rate = Some(fee_rate);
+        self
+    }
+
+    /// Return the inner database
+    pub fn into_inner(self) -> D {
+        self.database.into_inner().unwrap()
+    }
+}
+
+#[maybe_async]
+impl<D: Database + Send> Blockchain for OfflineBlockchain<D> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        HashSet::new()
+    }
+
+    fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+        Err(Error::Offline)
+    }
+
+    fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+        self.fee_rate.ok_or(Error::Offline)
+    }
+}
+
+#[maybe_async]
+impl<D: Database + Send> GetHeight for OfflineBlockchain<D> {
+    /// Return the height of the last sync
+    fn get_height(&self) -> Result<u32, Error> {
+        match self.database.lock().unwrap().get_sync_time()? {
+            Some(sync_time) => Ok(sync_time.block_time.height),
+            None => Err(Error::Offline),
+        }
+    }
+}
+
+#[maybe_async]
+impl<D: Database + Send> GetTx for OfflineBlockchain<D> {
+    /// Return the transaction if it's stored in the database
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        self.database.lock().unwrap().get_raw_tx(txid)
+    }
+}
+
+#[maybe_async]
+impl<D: Database + Send> GetBlockHash for OfflineBlockchain<D> {
+    fn get_block_hash(&self, _height: u64) -> Result<BlockHash, Error> {
+        Err(Error::Offline)
+    }
+}
+
+#[maybe_async]
+impl<D: Database + Send> WalletSync for OfflineBlockchain<D> {
+    fn wallet_setup<B: BatchDatabase>(
+        &self,
+        _database: &mut B,
+        _progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use super::*;
+    use crate::database::{BatchOperations, MemoryDatabase, SyncTime};
+    use crate::BlockTime;
+
+    #[test]
+    fn serves_from_database() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let mut database = MemoryDatabase::new();
+        database.set_raw_tx(&tx).unwrap();
+
+        let blockchain = OfflineBlockchain::new(database);
+        assert_eq!(blockchain.get_tx(&tx.txid()).unwrap(), Some(tx.clone()));
+        assert!(matches!(blockchain.get_height(), Err(Error::Offline)));
+        assert!(matches!(blockchain.estimate_fee(1), Err(Error::Offline)));
+        assert!(matches!(blockchain.broadcast(&tx), Err(Error::Offline)));
+
+        let mut database = blockchain.into_inner();
+        database
+            .set_sync_time(SyncTime {
+                block_time: BlockTime {
+                    height: 100,
+                    timestamp: 1_000,
+                },
+            })
+            .unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+        let blockchain = OfflineBlockchain::new(database).with_fee_rate(fee_rate);
+        assert_eq!(blockchain.get_height().unwrap(), 100);
+        assert_eq!(blockchain.estimate_fee(1).unwrap(), fee_rate);
+    }
+}
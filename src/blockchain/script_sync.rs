@@ -27,6 +27,18 @@ pub enum Request<'a, D: BatchDatabase> {
 
 /// starts a sync
 pub fn start<D: BatchDatabase>(db: &D, stop_gap: usize) -> Result<Request<'_, D>, Error> {
+    start_with_stop_gaps(db, stop_gap, &HashMap::new(), 0)
+}
+
+/// starts a sync, using the stop gaps in `stop_gaps` for the keychains listed there and
+/// `stop_gap` for the others. `gap_overshoot` more scripts are scanned after reaching the stop
+/// gap, as a safety margin.
+pub fn start_with_stop_gaps<'a, D: BatchDatabase>(
+    db: &'a D,
+    stop_gap: usize,
+    stop_gaps: &HashMap<KeychainKind, usize>,
+    gap_overshoot: usize,
+) -> Result<Request<'a, D>, Error> {
     use rand::seq::SliceRandom;
     let mut keychains = vec![KeychainKind::Internal, KeychainKind::External];
     // shuffling improve privacy, the server doesn't know my first request is from my internal or external addresses
@@ -43,7 +55,10 @@ pub fn start<D: BatchDatabase>(db: &D, stop_gap: usize) -> Result<Request<'_, D>
         initial_scripts_needed: scripts_needed.len(),
         scripts_needed,
         script_index: 0,
-        stop_gap,
+        stop_gap: stop_gaps.get(&keychain).copied().unwrap_or(stop_gap),
+        default_stop_gap: stop_gap,
+        stop_gaps: stop_gaps.clone(),
+        gap_overshoot,
         keychain,
         next_keychains: keychains,
     }))
@@ -55,6 +70,9 @@ pub struct ScriptReq<'a, D: BatchDatabase> {
     initial_scripts_needed: usize, // if this is 1, we assume the descriptor is not derivable
     scripts_needed: VecDeque<Script>,
     stop_gap: usize,
+    default_stop_gap: usize,
+    stop_gaps: HashMap<KeychainKind, usize>,
+    gap_overshoot: usize,
     keychain: KeychainKind,
     next_keychains: Vec<KeychainKind>,
 }
@@ -65,6 +83,27 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
         self.scripts_needed.iter()
     }
 
+    /// The keychain the requested scripts belong to
+    pub fn keychain(&self) -> KeychainKind {
+        self.keychain
+    }
+
+    /// Number of scripts of the current keychain already scanned
+    pub fn scanned(&self) -> usize {
+        self.script_index
+    }
+
+    /// Number of scripts scanned since the last one with transactions
+    pub fn current_gap(&self) -> usize {
+        let last = self
+            .state
+            .last_active_index
+            .get(&self.keychain)
+            .map(|&l| l + 1)
+            .unwrap_or(0);
+        self.script_index - last
+    }
+
     pub fn satisfy(
         mut self,
         // we want to know the txids assoiciated with the script and their height
@@ -77,6 +116,14 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
                 script
             );
             if !txid_list.is_empty() {
+                let gap = self.current_gap();
+                if gap >= self.stop_gap {
+                    warn!(
+                        "found transactions at index {} of keychain {:?} after {} unused scripts, \
+                         the stop gap of {} is too small",
+                        self.script_index, self.keychain, gap, self.stop_gap
+                    );
+                }
                 // the address is active
                 self.state
                     .last_active_index
@@ -145,12 +192,13 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
                 return Ok(Request::Script(self));
             }
 
-            if last > 0 && current_gap < self.stop_gap {
+            let required_gap = self.stop_gap + self.gap_overshoot;
+            if last > 0 && current_gap < required_gap {
                 // current gap is not large enough to stop, but we are unable to keep checking since
                 // we have exhausted cached scriptPubKeys, so return error
                 let err = MissingCachedScripts {
                     last_count: self.script_index,
-                    missing_count: self.stop_gap - current_gap,
+                    missing_count: required_gap - current_gap,
                 };
                 return Err(Error::MissingCachedScripts(err));
             }
@@ -173,6 +221,11 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
                 .collect::<VecDeque<_>>();
 
             self.keychain = keychain;
+            self.stop_gap = self
+                .stop_gaps
+                .get(&keychain)
+                .copied()
+                .unwrap_or(self.default_stop_gap);
             self.script_index = 0;
             self.initial_scripts_needed = scripts_needed.len();
             self.scripts_needed = scripts_needed;
@@ -457,11 +510,14 @@ fn make_txs_consistent(txs: &[TransactionDetails]) -> Vec<&TransactionDetails> {
         }
     }
 
-    utxo_index
+    let mut txs = utxo_index
         .into_iter()
         .map(|(_, tx)| (tx.txid, tx))
         .collect::<HashMap<_, _>>()
         .into_iter()
         .map(|(_, tx)| tx)
-        .collect()
+        .collect::<Vec<_>>();
+    // don't let the order of the hashmaps leak into the order of the database updates
+    txs.sort_unstable_by_key(|tx| tx.txid);
+    txs
 }
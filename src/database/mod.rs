@@ -48,7 +48,11 @@ pub use memory::MemoryDatabase;
 
 /// Blockchain state at the time of syncing
 ///
-/// Contains only the block time and height at the moment
+/// Contains only the block time and height at the moment. Both are written together by
+/// [`Wallet::sync`](crate::wallet::Wallet::sync), only after the backend committed the changes of
+/// the whole sync, with the height of the tip when the sync started. A failed or interrupted sync
+/// leaves the previous, lower, one in place: an incremental sync starting from it fetches some
+/// blocks again but never skips one, also after a restart.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SyncTime {
     /// Block timestamp and height at the time of sync
@@ -152,6 +156,8 @@ pub trait Database: BatchOperations {
     /// Return the last derivation index for a keychain.
     fn get_last_index(&self, keychain: KeychainKind) -> Result<Option<u32>, Error>;
     /// Return the sync time, if present
+    ///
+    /// This is the time of the last successful sync, see [`SyncTime`].
     fn get_sync_time(&self) -> Result<Option<SyncTime>, Error>;
 
     /// Increment the last derivation index for a keychain and return it
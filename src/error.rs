@@ -86,6 +86,13 @@ pub enum Error {
         /// found network, for example the network of the bitcoin node
         found: Network,
     },
+    /// The backend serves a different network than the one it was configured for
+    NetworkMismatch {
+        /// network the backend was configured for
+        expected: Network,
+        /// network of the genesis block served by the backend
+        found: Network,
+    },
     #[cfg(feature = "verify")]
     /// Transaction verification error
     Verification(crate::wallet::verify::VerifyError),
@@ -94,6 +101,10 @@ pub enum Error {
     InvalidProgressValue(f32),
     /// Progress update error (maybe the channel has been closed)
     ProgressUpdateError,
+    /// The sync was canceled before completing
+    Canceled,
+    /// The backend is offline and the requested data isn't available locally
+    Offline,
     /// Requested outpoint doesn't exist in the tx (vout greater than available outputs)
     InvalidOutpoint(OutPoint),
 
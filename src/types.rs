@@ -46,7 +46,9 @@ impl AsRef<[u8]> for KeychainKind {
 }
 
 /// Fee rate
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+///
+/// Serialized as a number of satoshi/vbyte, e.g. `2.5`.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 // Internally stored as satoshi/vbyte
 pub struct FeeRate(f32);
 
@@ -112,6 +114,11 @@ impl FeeRate {
         self.0
     }
 
+    /// Return the value as satoshi/kvbyte, the unit used by Bitcoin Core
+    pub fn as_sat_per_kvb(&self) -> f32 {
+        self.0 * 1000.0_f32
+    }
+
     /// Calculate absolute fee in Satoshis using size in weight units.
     pub fn fee_wu(&self, wu: usize) -> u64 {
         self.fee_vb(wu.vbytes())
@@ -248,7 +255,9 @@ pub struct TransactionDetails {
 }
 
 /// Block height and timestamp of a block
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+///
+/// Ordered by `height` first and then by `timestamp`, i.e. by position in the chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct BlockTime {
     /// confirmation block height
     pub height: u32,
@@ -334,6 +343,7 @@ impl std::iter::Sum for Balance {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn can_store_feerate_in_const() {
@@ -393,9 +403,52 @@ mod tests {
         assert!((fee.as_sat_per_vb() - 1.0).abs() < f32::EPSILON);
     }
 
+    #[test]
+    fn test_fee_as_sat_per_kvb() {
+        let fee = FeeRate::from_sat_per_vb(2.0);
+        assert!((fee.as_sat_per_kvb() - 2000.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_fee_serde() {
+        let fee = FeeRate::from_sat_per_vb(2.3);
+        assert_eq!(serde_json::to_string(&fee).unwrap(), "2.3");
+
+        let estimates: HashMap<u16, FeeRate> = vec![(1, fee), (6, FeeRate::from_sat_per_vb(1.1))]
+            .into_iter()
+            .collect();
+        let json = serde_json::to_string(&estimates).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HashMap<u16, FeeRate>>(&json).unwrap(),
+            estimates
+        );
+    }
+
     #[test]
     fn test_fee_from_sat_per_kwu() {
         let fee = FeeRate::from_sat_per_kwu(250.0);
         assert!((fee.as_sat_per_vb() - 1.0).abs() < f32::EPSILON);
     }
+
+    #[test]
+    fn test_block_time_ordering() {
+        let block_time = |height, timestamp| BlockTime { height, timestamp };
+        let mut times = vec![
+            block_time(2, 100),
+            block_time(1, 300),
+            block_time(2, 50),
+            block_time(1, 200),
+        ];
+        times.sort();
+        assert_eq!(
+            times,
+            vec![
+                block_time(1, 200),
+                block_time(1, 300),
+                block_time(2, 50),
+                block_time(2, 100),
+            ]
+        );
+        assert!(Some(block_time(0, 0)) > None);
+    }
 }
@@ -106,6 +106,8 @@ pub struct Wallet<D> {
     network: Network,
 
     database: RefCell<D>,
+    // scripts already derived from the descriptors, by keychain and derivation index
+    script_cache: RefCell<HashMap<(KeychainKind, u32), Script>>,
 
     secp: SecpCtx,
 }
@@ -239,6 +241,7 @@ where
             address_validators: Vec::new(),
             network,
             database: RefCell::new(database),
+            script_cache: RefCell::new(HashMap::new()),
             secp,
         })
     }
@@ -1375,6 +1378,16 @@ where
         Ok(())
     }
 
+    /// Clear the in-memory cache of the scripts derived from the descriptors
+    ///
+    /// The scripts are cached the first time they're derived while filling the address cache in
+    /// the database, so that they aren't derived again when the database needs to be refilled.
+    /// The descriptors of a wallet can't change, so this is never needed for correctness, only to
+    /// release the memory.
+    pub fn clear_script_cache(&self) {
+        self.script_cache.borrow_mut().clear();
+    }
+
     fn cache_addresses(
         &self,
         keychain: KeychainKind,
@@ -1393,12 +1406,12 @@ where
         let mut address_batch = self.database.borrow().begin_batch();
 
         let start_time = time::Instant::new();
+        let mut script_cache = self.script_cache.borrow_mut();
         for i in from..(from + count) {
-            address_batch.set_script_pubkey(
-                &descriptor.as_derived(i, &self.secp).script_pubkey(),
-                keychain,
-                i,
-            )?;
+            let script_pubkey = script_cache
+                .entry((keychain, i))
+                .or_insert_with(|| descriptor.as_derived(i, &self.secp).script_pubkey());
+            address_batch.set_script_pubkey(script_pubkey, keychain, i)?;
         }
 
         info!(
@@ -1751,6 +1764,9 @@ where
     }
 
     /// Sync the internal database with the blockchain
+    ///
+    /// Once every change is written, the height of the tip when the sync started and the current
+    /// time are stored as the [`SyncTime`] of the database. It is left untouched if the sync fails.
     #[maybe_async]
     pub fn sync<B: WalletSync + GetHeight>(
         &self,
@@ -1771,6 +1787,10 @@ where
         let run_setup = self.ensure_addresses_cached(CACHE_ADDR_BATCH_SIZE)?;
         debug!("run_setup: {}", run_setup);
 
+        // The sync sees at least the blocks up to this height, while a tip fetched after it could
+        // include blocks it didn't see, which an incremental sync would then skip
+        let tip_height = maybe_await!(blockchain.get_height())?;
+
         // TODO: what if i generate an address first and cache some addresses?
         // TODO: we should sync if generating an address triggers a new batch to be stored
 
@@ -1819,7 +1839,7 @@ where
 
         let sync_time = SyncTime {
             block_time: BlockTime {
-                height: maybe_await!(blockchain.get_height())?,
+                height: tip_height,
                 timestamp: time::get_timestamp(),
             },
         };
@@ -2029,6 +2049,32 @@ pub(crate) mod test {
             .is_some());
     }
 
+    #[test]
+    fn test_script_cache() {
+        let db = MemoryDatabase::new();
+        let wallet = Wallet::new("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+
+        assert!(wallet.ensure_addresses_cached(10).unwrap());
+        assert_eq!(wallet.script_cache.borrow().len(), 10);
+        let script_pubkey = wallet.script_cache.borrow()[&(KeychainKind::External, 9)].clone();
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_script_pubkey_from_path(KeychainKind::External, 9)
+                .unwrap(),
+            Some(script_pubkey)
+        );
+
+        // refilling the database reuses the scripts derived so far
+        assert!(wallet.ensure_addresses_cached(20).unwrap());
+        assert_eq!(wallet.script_cache.borrow().len(), 20);
+
+        wallet.clear_script_cache();
+        assert!(wallet.script_cache.borrow().is_empty());
+        assert!(!wallet.ensure_addresses_cached(20).unwrap());
+    }
+
     pub(crate) fn get_test_wpkh() -> &'static str {
         "wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)"
     }